@@ -0,0 +1,230 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use tower_lsp::lsp_types::{Position, Range, TextEdit};
+use unicode_width::UnicodeWidthStr;
+
+use crate::index::{body_lines, pattern};
+use crate::offsets;
+
+static LIST_ITEM_RE: LazyLock<Regex> = LazyLock::new(|| {
+    pattern(
+        "list item",
+        r"^(?<indent>\s*)(?:(?<bullet>[-*+])|(?<number>\d+)(?<delim>[.)]))\s+(?<checkbox>\[[ xX]\]\s*)?(?<text>.*)$",
+    )
+});
+
+static SEPARATOR_ROW_RE: LazyLock<Regex> =
+    LazyLock::new(|| pattern("table separator", r"^\|[-:+| ]*-[-:+| ]*$"));
+
+/// Compile this module's patterns; see `index::compile_patterns`.
+pub fn compile_patterns() {
+    LazyLock::force(&LIST_ITEM_RE);
+    LazyLock::force(&SEPARATOR_ROW_RE);
+}
+
+/// The edits to make after Enter is pressed at the end of a list item, with
+/// the cursor now at `position`: start the new line with the next marker (an
+/// unticked box for a checklist), or, if the item was left empty, remove its
+/// marker to end the list. Lines in code blocks and front matter are left be.
+pub fn continue_list(content: &str, position: Position) -> Vec<TextEdit> {
+    let lines: Vec<&str> = content.lines().collect();
+    let row = position.line as usize;
+    let previous = match row.checked_sub(1) {
+        Some(previous) => match body_lines(content).into_iter().find(|(r, _)| *r == previous) {
+            Some((_, line)) => line,
+            None => return vec![],
+        },
+        None => return vec![],
+    };
+    let cap = match LIST_ITEM_RE.captures(previous) {
+        Some(cap) => cap,
+        None => return vec![],
+    };
+
+    if cap["text"].trim().is_empty() {
+        return vec![TextEdit {
+            range: offsets::range(previous, row - 1, 0, previous.len()),
+            new_text: String::new(),
+        }];
+    }
+
+    let mut marker = match (cap.name("bullet"), cap.name("number")) {
+        (Some(bullet), _) => bullet.as_str().to_string(),
+        (None, Some(number)) => match number.as_str().parse::<u64>() {
+            Ok(n) => format!("{}{}", n + 1, &cap["delim"]),
+            Err(_) => return vec![],
+        },
+        (None, None) => return vec![],
+    };
+    if cap.name("checkbox").is_some() {
+        marker.push_str(" [ ]");
+    }
+
+    // Replace whatever indentation the editor has already put on the new line.
+    let line = lines.get(row).copied().unwrap_or("");
+    let indent = line.len() - line.trim_start().len();
+    vec![TextEdit {
+        range: Range::new(
+            Position::new(position.line, 0),
+            Position::new(position.line, offsets::utf16_col(line, indent)),
+        ),
+        new_text: format!("{}{} ", &cap["indent"], marker),
+    }]
+}
+
+/// The edits that align every `|` table in a note: each cell is padded to
+/// its column's width, and separator rows are redrawn to match. Widths are
+/// measured as displayed, so wide characters line up. Only lines that change
+/// get an edit; everything outside tables is left alone.
+pub fn format_tables(content: &str) -> Vec<TextEdit> {
+    tables(content).iter().flat_map(|table| format_table(table)).collect()
+}
+
+/// Like `format_tables`, but only for tables with at least one row in
+/// `range`. Those are formatted whole, so a table that's only partly
+/// selected still comes out aligned.
+pub fn format_tables_in(content: &str, range: &Range) -> Vec<TextEdit> {
+    let (first, last) = (range.start.line as usize, range.end.line as usize);
+    tables(content)
+        .iter()
+        .filter(|table| table.iter().any(|(row, _)| first <= *row && *row <= last))
+        .flat_map(|table| format_table(table))
+        .collect()
+}
+
+/// The tables in a note, each as its rows: runs of lines starting with `|`.
+fn tables(content: &str) -> Vec<Vec<(usize, &str)>> {
+    let mut tables = Vec::new();
+    let mut table: Vec<(usize, &str)> = Vec::new();
+    let mut previous_row = None;
+
+    for (row, line) in body_lines(content) {
+        let is_row = line.trim_start().starts_with('|');
+        // A table ends at the first line that isn't a row, including code
+        // blocks and front matter, which `body_lines` skips over.
+        if (!is_row || previous_row.is_some_and(|p| p + 1 != row)) && !table.is_empty() {
+            tables.push(std::mem::take(&mut table));
+        }
+        if is_row {
+            table.push((row, line));
+        }
+        previous_row = Some(row);
+    }
+    if !table.is_empty() {
+        tables.push(table);
+    }
+
+    tables
+}
+
+/// How the text in a table column is aligned, going by its separator cell.
+#[derive(Clone, Copy, PartialEq)]
+enum Align {
+    /// No colons: left, but written without one.
+    Unset,
+    Left,
+    Centre,
+    Right,
+}
+
+/// The edits to align a single table, given as its rows.
+fn format_table(rows: &[(usize, &str)]) -> Vec<TextEdit> {
+    let first = match rows.first() {
+        Some((_, line)) => *line,
+        None => return vec![],
+    };
+    let indent = &first[..first.len() - first.trim_start().len()];
+
+    // Each row is either a separator or its cells, without the outer pipes.
+    let parsed: Vec<Option<Vec<&str>>> = rows
+        .iter()
+        .map(|(_, line)| {
+            let line = line.trim();
+            if SEPARATOR_ROW_RE.is_match(line) {
+                return None;
+            }
+            let inner = line.strip_prefix('|').unwrap_or(line);
+            let inner = inner.strip_suffix('|').unwrap_or(inner);
+            Some(inner.split('|').map(str::trim).collect())
+        })
+        .collect();
+
+    let columns = parsed.iter().flatten().map(Vec::len).max().unwrap_or(0);
+    if columns == 0 {
+        return vec![];
+    }
+    let mut widths = vec![1; columns];
+    for cells in parsed.iter().flatten() {
+        for (width, cell) in widths.iter_mut().zip(cells) {
+            *width = (*width).max(cell.width());
+        }
+    }
+
+    // Alignment comes from the first separator, as `:--`, `:-:` or `--:`.
+    let aligns: Vec<Align> = rows
+        .iter()
+        .zip(&parsed)
+        .find(|(_, cells)| cells.is_none())
+        .map(|((_, line), _)| {
+            let line = line.trim();
+            let inner = line.strip_prefix('|').unwrap_or(line);
+            let inner = inner.strip_suffix('|').unwrap_or(inner);
+            inner
+                .split(['|', '+'])
+                .map(|cell| match (cell.trim().starts_with(':'), cell.trim().ends_with(':')) {
+                    (true, true) => Align::Centre,
+                    (true, false) => Align::Left,
+                    (false, true) => Align::Right,
+                    (false, false) => Align::Unset,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let align = |column: usize| aligns.get(column).copied().unwrap_or(Align::Unset);
+
+    let mut edits = Vec::new();
+    for ((row, line), cells) in rows.iter().zip(&parsed) {
+        let formatted = match cells {
+            Some(cells) => {
+                let padded: Vec<String> = (0..columns)
+                    .map(|column| {
+                        let cell = cells.get(column).copied().unwrap_or("");
+                        let space = widths[column] - cell.width();
+                        let (before, after) = match align(column) {
+                            Align::Unset | Align::Left => (0, space),
+                            Align::Centre => (space / 2, space - space / 2),
+                            Align::Right => (space, 0),
+                        };
+                        format!(" {}{}{} ", " ".repeat(before), cell, " ".repeat(after))
+                    })
+                    .collect();
+                format!("{}|{}|", indent, padded.join("|"))
+            }
+            None => {
+                // Keep the style of junction the separator was written with.
+                let junction = if line.contains('+') { "+" } else { "|" };
+                let dashes: Vec<String> = (0..columns)
+                    .map(|column| {
+                        let dashes = "-".repeat(widths[column]);
+                        match align(column) {
+                            Align::Unset => format!("-{}-", dashes),
+                            Align::Left => format!(":{}-", dashes),
+                            Align::Centre => format!(":{}:", dashes),
+                            Align::Right => format!("-{}:", dashes),
+                        }
+                    })
+                    .collect();
+                format!("{}|{}|", indent, dashes.join(junction))
+            }
+        };
+
+        if formatted != *line {
+            edits.push(TextEdit {
+                range: offsets::range(line, *row, 0, line.len()),
+                new_text: formatted,
+            });
+        }
+    }
+    edits
+}
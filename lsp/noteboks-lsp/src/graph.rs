@@ -0,0 +1,177 @@
+use std::collections::{BTreeSet, HashSet};
+use std::path::PathBuf;
+
+use crate::index::{Index, NoteID, NoteKind};
+
+/// A note in the link graph.
+pub struct Node {
+    pub id: NoteID,
+    pub title: String,
+    pub path: Option<PathBuf>,
+}
+
+/// The link graph: every note, and an edge for every note-to-note link or
+/// tag. Links that don't resolve are kept separately.
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: BTreeSet<(NoteID, NoteID)>,
+    pub broken: BTreeSet<(NoteID, NoteID)>,
+}
+
+impl Graph {
+    pub fn of_index(index: &Index) -> Self {
+        let mut edges = BTreeSet::new();
+        let mut broken = BTreeSet::new();
+        for note in index.notes.values() {
+            for link in &note.outlinks {
+                if let Some(target) = index.resolve_link(link) {
+                    edges.insert((note.id.clone(), target.id.clone()));
+                }
+            }
+            // Tags that don't name a note aren't broken, so only `[[links]]`
+            // count here.
+            for (link, _) in index.broken_links(note) {
+                broken.insert((note.id.clone(), link));
+            }
+        }
+
+        let nodes = index
+            .notes
+            .values()
+            .map(|n| Node { id: n.id.clone(), title: n.display_title(), path: index.path_of(n) })
+            .collect();
+        Graph { nodes, edges, broken }
+    }
+
+    /// Just the notes within `depth` links of `center`, in either direction,
+    /// and the links between them.
+    pub fn neighbourhood(self, center: &NoteID, depth: usize) -> Self {
+        let mut reached: HashSet<NoteID> = HashSet::from([center.clone()]);
+        let mut frontier = vec![center.clone()];
+        for _ in 0..depth {
+            let mut next = Vec::new();
+            for (from, to) in &self.edges {
+                for (here, there) in [(from, to), (to, from)] {
+                    if frontier.contains(here) && reached.insert(there.clone()) {
+                        next.push(there.clone());
+                    }
+                }
+            }
+            frontier = next;
+        }
+
+        Graph {
+            nodes: self.nodes.into_iter().filter(|n| reached.contains(&n.id)).collect(),
+            edges: self
+                .edges
+                .into_iter()
+                .filter(|(from, to)| reached.contains(from) && reached.contains(to))
+                .collect(),
+            broken: self.broken.into_iter().filter(|(from, _)| reached.contains(from)).collect(),
+        }
+    }
+
+    /// Render the graph in Graphviz's DOT language, with nodes shaped and
+    /// coloured by kind. Broken links become dashed edges to grey phantom
+    /// nodes if `include_broken` is set, and are left out otherwise.
+    pub fn to_dot(&self, include_broken: bool) -> String {
+        let mut dot = String::from("digraph noteboks {\n");
+        for Node { id, .. } in &self.nodes {
+            let (shape, colour) = node_style(&id.kind);
+            dot.push_str(&format!(
+                "  {} [label={}, shape={}, color={}];\n",
+                node_id(id),
+                quote(&id.name),
+                shape,
+                colour
+            ));
+        }
+
+        if include_broken {
+            let phantoms: BTreeSet<&NoteID> = self.broken.iter().map(|(_, to)| to).collect();
+            for id in phantoms {
+                dot.push_str(&format!(
+                    "  {} [label={}, shape=ellipse, color=grey, fontcolor=grey, style=dashed];\n",
+                    phantom_id(id),
+                    quote(&id.name)
+                ));
+            }
+        }
+
+        for (from, to) in &self.edges {
+            dot.push_str(&format!("  {} -> {};\n", node_id(from), node_id(to)));
+        }
+        if include_broken {
+            for (from, to) in &self.broken {
+                dot.push_str(&format!(
+                    "  {} -> {} [style=dashed, color=grey];\n",
+                    node_id(from),
+                    phantom_id(to)
+                ));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render the graph as JSON: `{ nodes: [{ id, name, kind, title, path,
+    /// inDegree, outDegree }], edges: [{ from, to }] }`, where edges refer to
+    /// node IDs (file names). Broken links are included, to IDs with no node,
+    /// if `include_broken`.
+    pub fn to_json(&self, include_broken: bool) -> serde_json::Value {
+        let file_name = |id: &NoteID| id.to_filename().to_string_lossy().to_string();
+        let nodes: Vec<serde_json::Value> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                serde_json::json!({
+                    "id": file_name(&node.id),
+                    "name": node.id.name,
+                    "kind": node.id.kind.to_str(),
+                    "title": node.title,
+                    "path": node.path,
+                    "inDegree": self.edges.iter().filter(|(_, to)| *to == node.id).count(),
+                    "outDegree": self.edges.iter().filter(|(from, _)| *from == node.id).count(),
+                })
+            })
+            .collect();
+        let broken = self.broken.iter().filter(|_| include_broken);
+        let edges: Vec<serde_json::Value> = self
+            .edges
+            .iter()
+            .chain(broken)
+            .map(|(from, to)| serde_json::json!({ "from": file_name(from), "to": file_name(to) }))
+            .collect();
+
+        serde_json::json!({ "nodes": nodes, "edges": edges })
+    }
+}
+
+/// How a note of each kind is drawn, as a DOT `(shape, color)`.
+fn node_style(kind: &NoteKind) -> (&'static str, &'static str) {
+    match kind {
+        NoteKind::Note => ("ellipse", "black"),
+        NoteKind::Article => ("note", "blue"),
+        NoteKind::List => ("box", "darkgreen"),
+        NoteKind::Index => ("doubleoctagon", "red"),
+        NoteKind::Person => ("house", "purple"),
+        NoteKind::Dump => ("cylinder", "orange"),
+        NoteKind::Custom(_) | NoteKind::Any => ("ellipse", "black"),
+    }
+}
+
+/// A DOT identifier for a note, unique across kinds.
+fn node_id(id: &NoteID) -> String {
+    quote(&id.to_filename().to_string_lossy())
+}
+
+/// A DOT identifier for a link target that doesn't exist.
+fn phantom_id(id: &NoteID) -> String {
+    quote(&format!("missing:{}", id.to_filename().to_string_lossy()))
+}
+
+/// Quote a string as a DOT identifier.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
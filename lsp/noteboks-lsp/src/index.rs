@@ -1,6 +1,9 @@
 use std::{
-    collections::{BTreeMap, HashSet},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    panic::AssertUnwindSafe,
     path::{Path, PathBuf},
+    sync::{LazyLock, RwLock},
 };
 
 use lsp_textdocument::FullTextDocument;
@@ -10,13 +13,52 @@ use tower_lsp::lsp_types::{
     VersionedTextDocumentIdentifier,
 };
 
+use crate::offsets::{self, utf16_col};
+
 /// Normalise a note name for consistent lookup:
 /// lowercase, collapse spaces/underscores/hyphens into a single hyphen.
 pub fn normalize_name(s: &str) -> String {
     let lower = s.to_lowercase();
     // Replace runs of whitespace, hyphens, or underscores with a single '-'
-    let re = Regex::new(r"[\s\-_]+").unwrap();
-    re.replace_all(&lower, "-").trim_matches('-').to_string()
+    SEPARATOR_RE.replace_all(&lower, "-").trim_matches('-').to_string()
+}
+
+// Patterns are compiled once, on first use, rather than on every call.
+// `compile_patterns` forces them all at startup, so a bad one fails there.
+static SEPARATOR_RE: LazyLock<Regex> = LazyLock::new(|| pattern("separator", r"[\s\-_]+"));
+pub static LINK_RE: LazyLock<Regex> = LazyLock::new(|| pattern("link", r"\[\[([^\]]+)\]\]"));
+pub static TAG_RE: LazyLock<Regex> =
+    LazyLock::new(|| pattern("tag", r"#([a-zA-Z][a-zA-Z0-9_\-]*)"));
+static HEADING_RE: LazyLock<Regex> =
+    LazyLock::new(|| pattern("heading", r"^(#{1,6})\s+(.*?)(?:\s+#+)?\s*$"));
+static CHECKBOX_RE: LazyLock<Regex> =
+    LazyLock::new(|| pattern("checkbox", r"^(\s*)[-*+]\s+\[([ xX])\]\s*(.*?)\s*$"));
+
+/// Compile one of the built-in patterns, naming it if it's invalid.
+pub fn pattern(name: &str, re: &str) -> Regex {
+    Regex::new(re).unwrap_or_else(|err| panic!("the {} pattern failed to compile: {}", name, err))
+}
+
+/// Compile every pattern in this module, so that any mistake in one is
+/// reported when the server starts rather than on some later edit.
+pub fn compile_patterns() {
+    for re in [&SEPARATOR_RE, &LINK_RE, &TAG_RE, &HEADING_RE, &CHECKBOX_RE, &LINK_TARGET_RE] {
+        LazyLock::force(re);
+    }
+}
+
+/// The text inside a `[[link]]`, with an optional `(kind)` suffix. Whatever
+/// is in the parentheses is captured, so an unknown kind can be reported
+/// rather than the whole link failing to match.
+static LINK_TARGET_RE: LazyLock<Regex> = LazyLock::new(|| {
+    pattern("link target", r"^(?<name>[_\-\?\:\/\\\w\d ]+)\s*(?:\((?<kind>[^()]*)\))?$")
+});
+
+/// User-defined kinds, as `(extension, display name)`. See `NoteKind::set_custom`.
+static CUSTOM_KINDS: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
+
+fn custom_kinds() -> std::sync::RwLockReadGuard<'static, Vec<(String, String)>> {
+    CUSTOM_KINDS.read().unwrap_or_else(|e| e.into_inner())
 }
 
 #[allow(dead_code)]
@@ -38,19 +80,38 @@ pub enum NoteKind {
     /// Information about a person.
     Person,
 
+    /// A daily log, named after its date.
+    Dump,
+
+    /// A kind defined in the user's settings, by its (lowercase) extension.
+    Custom(String),
+
     /// Unspecified kind — defaults to Note for resolution.
     Any,
 }
 
 impl NoteKind {
+    /// Every concrete kind, i.e. everything but `Any`.
+    pub const ALL: [NoteKind; 6] = [
+        NoteKind::Note,
+        NoteKind::Article,
+        NoteKind::List,
+        NoteKind::Index,
+        NoteKind::Person,
+        NoteKind::Dump,
+    ];
+
+    /// Parse a kind name or file extension, ignoring case.
     pub fn from_str(s: &str) -> Option<Self> {
-        match s {
+        let s = s.to_ascii_lowercase();
+        match s.as_str() {
             "note" => Some(NoteKind::Note),
             "article" => Some(NoteKind::Article),
             "list" => Some(NoteKind::List),
             "index" => Some(NoteKind::Index),
             "person" => Some(NoteKind::Person),
-            _ => None,
+            "dump" => Some(NoteKind::Dump),
+            _ => custom_kinds().iter().any(|(ext, _)| *ext == s).then_some(NoteKind::Custom(s)),
         }
     }
 
@@ -61,10 +122,43 @@ impl NoteKind {
             NoteKind::List => "list",
             NoteKind::Index => "index",
             NoteKind::Person => "person",
+            NoteKind::Dump => "dump",
+            NoteKind::Custom(ext) => ext,
             NoteKind::Any => "note",
         }
     }
 
+    /// A name for the kind to show the user: for custom kinds, the name they
+    /// were given in settings.
+    pub fn display_name(&self) -> String {
+        match self {
+            NoteKind::Custom(ext) => custom_kinds()
+                .iter()
+                .find(|(e, _)| e == ext)
+                .map(|(_, name)| name.clone())
+                .unwrap_or_else(|| ext.clone()),
+            kind => kind.to_str().to_string(),
+        }
+    }
+
+    /// Every concrete kind, including those defined in settings.
+    pub fn all() -> Vec<NoteKind> {
+        let custom: Vec<NoteKind> =
+            custom_kinds().iter().map(|(ext, _)| NoteKind::Custom(ext.clone())).collect();
+        NoteKind::ALL.into_iter().chain(custom).collect()
+    }
+
+    /// Define the custom kinds, as `(extension, display name)` pairs. Any
+    /// that clash with a built-in kind are ignored.
+    pub fn set_custom(kinds: Vec<(String, String)>) {
+        let kinds = kinds
+            .into_iter()
+            .map(|(ext, name)| (ext.trim_start_matches('.').to_ascii_lowercase(), name))
+            .filter(|(ext, _)| !ext.is_empty() && !NoteKind::ALL.iter().any(|k| k.to_str() == ext))
+            .collect();
+        *CUSTOM_KINDS.write().unwrap_or_else(|e| e.into_inner()) = kinds;
+    }
+
     pub fn from_path(path: &Path) -> Option<Self> {
         Self::from_str(path.extension()?.to_str()?)
     }
@@ -96,35 +190,237 @@ impl NoteID {
         PathBuf::from(format!("{}.{}", self.name, self.kind.to_str()))
     }
 
+    /// A `[[link]]` to this note, using its name with spaces.
+    pub fn to_link(&self) -> String {
+        format!("[[{}]]", link_text(&self.name.replace('-', " "), &self.kind))
+    }
+
     /// Parse a link target like "lambda calculus" or "modal logic (index)".
     pub fn from_link(link: &str) -> Option<Self> {
-        let re = Regex::new(
-            r"^(?<name>[_\-\?\:\/\\\w\d ]+)\s*(?<kind>(?:\((?:note|article|list|index|person)\))?)$",
-        )
-        .unwrap();
+        Self::parse_link(link).ok()
+    }
+
+    /// Like `from_link`, but saying why a link target doesn't parse.
+    pub fn parse_link(link: &str) -> Result<Self, LinkError> {
+        // Notes are identified by their file name wherever they are, so a
+        // path only needs its last component.
+        if is_path_link(link) {
+            return NoteID::from_path(Path::new(link.trim())).ok_or(LinkError::Empty);
+        }
 
-        re.captures(link).map(|c| {
-            let (_, [name, kind_str]) = c.extract();
+        if let Some(c) = LINK_TARGET_RE.captures(link) {
+            let name = normalize_name(c["name"].trim());
+            if name.is_empty() {
+                return Err(LinkError::Empty);
+            }
             // If no kind annotation is present, use Any so resolve_link can
             // search all extensions rather than assuming .note
-            let kind = NoteKind::from_str(kind_str.trim_matches(&['(', ')']))
-                .unwrap_or(NoteKind::Any);
+            let kind = match c.name("kind").map(|k| k.as_str().trim()) {
+                Some(kind) => match NoteKind::from_str(kind) {
+                    Some(kind) => kind,
+                    None => return Err(LinkError::UnknownKind(kind.to_string())),
+                },
+                None => NoteKind::Any,
+            };
+            return Ok(NoteID { name, kind });
+        }
 
-            NoteID {
-                name: normalize_name(name.trim()),
-                kind,
-            }
+        if link.trim().is_empty() {
+            return Err(LinkError::Empty);
+        }
+        let allowed = |c: char| c.is_alphanumeric() || "_-?:/\\ ".contains(c);
+        match link.chars().find(|c| !allowed(*c)) {
+            Some(c) => Err(LinkError::InvalidCharacter(c)),
+            None => Err(LinkError::Empty),
+        }
+    }
+}
+
+/// Whether a link target is the path to a note's file, like
+/// "./projects/ideas.note", rather than its name. Names can't contain a `.`,
+/// so anything ending in a note extension is a path.
+pub fn is_path_link(link: &str) -> bool {
+    NoteKind::from_path(Path::new(link.trim())).is_some()
+}
+
+/// Where the note name lies in the text of a link, in bytes: "lambda
+/// calculus (article)::History" gives the span of "lambda calculus". For a
+/// path, it's the file name without its extension.
+pub fn link_name_span(link: &str) -> Option<std::ops::Range<usize>> {
+    let (note, _) = split_anchor(link);
+    if is_path_link(note) {
+        let path = note.trim_end();
+        let start = path.rfind(['/', '\\']).map_or(path.len() - path.trim_start().len(), |i| i + 1);
+        let end = path.rfind('.')?;
+        return (start < end).then_some(start..end);
+    }
+    let name = LINK_TARGET_RE.captures(note)?.name("name")?;
+    let start = name.start() + (name.as_str().len() - name.as_str().trim_start().len());
+    let end = name.start() + name.as_str().trim_end().len();
+    (start < end).then_some(start..end)
+}
+
+/// Why a link target couldn't be parsed as a note ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    /// There's no name, as in `[[]]` or `[[ (note) ]]`.
+    Empty,
+    /// The name contains a character notes can't be named with.
+    InvalidCharacter(char),
+    /// The `(kind)` suffix isn't a known kind.
+    UnknownKind(String),
+    /// There's a `[[` with no `]]` after it on the line.
+    Unterminated,
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LinkError::Empty => write!(f, "the link has no note name"),
+            LinkError::InvalidCharacter(c) => write!(f, "note names can't contain '{}'", c),
+            LinkError::UnknownKind(kind) => write!(f, "'{}' isn't a kind of note", kind),
+            LinkError::Unterminated => write!(f, "the link isn't closed with ]]"),
+        }
+    }
+}
+
+/// The text inside a `[[link]]` to a note called `name`: the inverse of
+/// `NoteID::from_link`, e.g. "lambda calculus" or "modal logic (index)".
+pub fn link_text(name: &str, kind: &NoteKind) -> String {
+    match kind {
+        NoteKind::Any => name.to_string(),
+        kind => format!("{} ({})", name, kind.to_str()),
+    }
+}
+
+/// A calendar date, for naming daily notes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Today's date where the user is, so an evening's daily note isn't
+    /// tomorrow's.
+    pub fn today() -> Self {
+        use chrono::Datelike;
+
+        let today = chrono::Local::now().date_naive();
+        Date { year: today.year() as i64, month: today.month(), day: today.day() }
+    }
+
+    /// The date `days` days after 1970-01-01, using Howard Hinnant's
+    /// `civil_from_days` algorithm.
+    fn from_days(days: i64) -> Self {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Date { year, month, day }
+    }
+
+    /// The number of days since 1970-01-01; the inverse of `from_days`.
+    fn to_days(self) -> i64 {
+        let year = if self.month <= 2 { self.year - 1 } else { self.year };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = self.month as i64;
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+
+    pub fn yesterday(self) -> Self {
+        Self::from_days(self.to_days() - 1)
+    }
+
+    /// Format the date, replacing `YYYY`, `MM` and `DD` in `format`.
+    pub fn format(&self, format: &str) -> String {
+        format
+            .replace("YYYY", &format!("{:04}", self.year))
+            .replace("MM", &format!("{:02}", self.month))
+            .replace("DD", &format!("{:02}", self.day))
+    }
+}
+
+/// What a `[[link]]` points at: a note, and optionally a heading within it,
+/// as in `[[project ideas#Next steps]]` or `[[project ideas::Next steps]]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkTarget {
+    pub id: NoteID,
+    pub anchor: Option<String>,
+}
+
+/// Split link text into the note part and the anchor part, including the
+/// `::` or `#` separating them.
+pub fn split_anchor(link: &str) -> (&str, &str) {
+    let at = link.find("::").or_else(|| link.find('#')).unwrap_or(link.len());
+    link.split_at(at)
+}
+
+impl LinkTarget {
+    /// Parse a link target like "lambda calculus" or "modal logic (index)::History".
+    pub fn from_link(link: &str) -> Option<Self> {
+        let (note, anchor) = split_anchor(link);
+        let anchor = anchor.trim_start_matches("::").trim_start_matches('#').trim();
+        let anchor = Some(anchor.to_string()).filter(|a| !a.is_empty());
+        Some(LinkTarget {
+            id: NoteID::from_link(note.trim())?,
+            anchor,
         })
     }
 }
 
+/// A markdown heading and the section it introduces.
+pub struct Heading {
+    pub level: usize,
+    pub text: String,
+    /// The heading line itself.
+    pub range: Range,
+    /// From the heading up to the next heading of the same or a higher level,
+    /// or the end of the note.
+    pub section: Range,
+}
+
+/// A task: a heading starting with a task keyword (`## TODO Write intro`), or
+/// a checkbox list item (`- [ ] Write intro`).
+pub struct Task {
+    pub text: String,
+    /// The task keyword, e.g. `TODO` or `DONE`. Checkboxes are `TODO` when
+    /// empty and `DONE` when ticked.
+    pub state: String,
+    pub done: bool,
+    pub range: Range,
+}
+
 pub struct Note {
     pub id: NoteID,
     pub path: Option<PathBuf>,
     pub document: Option<FullTextDocument>,
+    /// The human-readable title: from front matter, else a `#+title:` line,
+    /// else the first top-level heading. The ID still comes from the file.
     pub title: Option<String>,
+    /// The kind named by a `#+kind:` line, if there's one naming a kind. The
+    /// ID's kind still comes from the file's extension.
+    pub declared_kind: Option<NoteKind>,
     pub aliases: Vec<String>,
-    pub outlinks: HashSet<NoteID>,
+    /// The notes it links to, as written, in order so that anything built
+    /// from them comes out the same every time.
+    pub outlinks: BTreeSet<NoteID>,
+    /// Every link and tag in the document, with its range, in document order.
+    pub links: Vec<(NoteID, Range)>,
+    /// Every `#tag` used in the document, normalised.
+    pub tags: HashSet<String>,
+    /// Whether the editor has this note open, in which case `document` holds
+    /// the (possibly unsaved) buffer rather than what's on disk.
+    pub open: bool,
 }
 
 impl Note {
@@ -134,23 +430,275 @@ impl Note {
             path: None,
             document: None,
             title: None,
+            declared_kind: None,
             aliases: Vec::new(),
-            outlinks: HashSet::new(),
+            outlinks: BTreeSet::new(),
+            links: Vec::new(),
+            tags: HashSet::new(),
+            open: false,
         }
     }
 
     pub fn of_file(path: &Path) -> Option<Self> {
+        // Check the extension first, so unrelated files are never read.
+        let id = NoteID::from_path(path)?;
         let content = std::fs::read_to_string(path).ok()?;
         let document = FullTextDocument::new(String::from("noteboks"), 0, content);
         let mut note = Note {
             path: Some(path.to_path_buf()),
             document: Some(document),
-            ..Note::new(NoteID::from_path(path)?)
+            ..Note::new(id)
         };
-        note.update_links();
+        // One unreadable note shouldn't take the whole scan down with it: if
+        // extracting its links panics, index it without any.
+        let extracted = std::panic::catch_unwind(AssertUnwindSafe(|| note.update_links()));
+        if extracted.is_err() {
+            note.links.clear();
+            note.outlinks.clear();
+            note.tags.clear();
+        }
         Some(note)
     }
 
+    /// The note's text: the editor's buffer, unsaved changes and all, if
+    /// there is one, and otherwise what's on disk.
+    pub fn content(&self) -> Cow<'_, str> {
+        match self.document.as_ref() {
+            Some(doc) => Cow::Borrowed(doc.get_content(None)),
+            None => {
+                let read = self.path.as_ref().and_then(|path| std::fs::read_to_string(path).ok());
+                Cow::Owned(read.unwrap_or_default())
+            }
+        }
+    }
+
+    /// The note's headings, in document order, with their sections.
+    pub fn headings(&self) -> Vec<Heading> {
+        let content = self.content();
+        let lines: Vec<&str> = content.lines().collect();
+        let found = scan_headings(&content);
+
+        found
+            .iter()
+            .enumerate()
+            .map(|(i, (level, text, range))| {
+                let end_row = found[i + 1..]
+                    .iter()
+                    .find(|(l, _, _)| l <= level)
+                    .map(|(_, _, r)| r.start.line - 1)
+                    .unwrap_or(lines.len().saturating_sub(1) as u32);
+                let end_col = lines.get(end_row as usize).map_or(0, |l| utf16_col(l, l.len()));
+                Heading {
+                    level: *level,
+                    text: text.clone(),
+                    range: *range,
+                    section: Range::new(range.start, Position::new(end_row, end_col)),
+                }
+            })
+            .collect()
+    }
+
+    /// The heading a link anchor refers to. Matching ignores case, spacing
+    /// and punctuation, as note names do.
+    pub fn heading(&self, anchor: &str) -> Option<Heading> {
+        let anchor = normalize_name(anchor);
+        self.headings()
+            .into_iter()
+            .find(|h| normalize_name(&h.text) == anchor)
+    }
+
+    /// The non-empty lines of a heading's section, after the heading itself,
+    /// cut off at `max_lines`.
+    pub fn section_excerpt(&self, heading: &Heading, max_lines: usize) -> Vec<String> {
+        let content = self.content();
+        content
+            .lines()
+            .enumerate()
+            .skip(heading.section.start.line as usize + 1)
+            .take_while(|(row, _)| *row <= heading.section.end.line as usize)
+            .map(|(_, line)| line)
+            .filter(|line| !line.trim().is_empty())
+            .take(max_lines)
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    /// The tasks in the note, in order.
+    pub fn tasks(&self) -> Vec<Task> {
+        let content = self.content();
+        let (open, done) = todo_keywords(&content);
+        let mut tasks = Vec::new();
+        for (row, line) in body_lines(&content) {
+            let range = offsets::range(
+                line,
+                row,
+                line.len() - line.trim_start().len(),
+                line.trim_end().len(),
+            );
+            if let Some(caps) = CHECKBOX_RE.captures(line) {
+                let ticked = &caps[2] != " ";
+                tasks.push(Task {
+                    text: caps[3].to_string(),
+                    state: if ticked { "DONE" } else { "TODO" }.to_string(),
+                    done: ticked,
+                    range,
+                });
+                continue;
+            }
+
+            let heading = match line.trim_start_matches('#').strip_prefix(' ') {
+                Some(text) if line.starts_with('#') => text.trim(),
+                _ => continue,
+            };
+            let (keyword, text) = heading.split_once(' ').unwrap_or((heading, ""));
+            let is_done = done.iter().any(|k| k == keyword);
+            if is_done || open.iter().any(|k| k == keyword) {
+                tasks.push(Task {
+                    text: text.trim().to_string(),
+                    state: keyword.to_string(),
+                    done: is_done,
+                    range,
+                });
+            }
+        }
+
+        tasks
+    }
+
+    /// Every `[[link]]` whose `(kind)` suffix isn't a kind of note, with what
+    /// the suffix says and where it is, parentheses included.
+    pub fn unknown_kinds(&self) -> Vec<(String, Range)> {
+        let content = self.content();
+        let lines: Vec<&str> = content.lines().collect();
+        scan_links(&content)
+            .into_iter()
+            .filter_map(|(text, range)| {
+                let (note, _) = split_anchor(&text);
+                // Matched trimmed, as `parse_link` does, so padding inside
+                // the brackets doesn't hide the suffix.
+                let padding = note.len() - note.trim_start().len();
+                let kind = LINK_TARGET_RE.captures(note.trim())?.name("kind")?;
+                if NoteKind::from_str(kind.as_str().trim()).is_some() {
+                    return None;
+                }
+                let row = range.start.line as usize;
+                let line = lines.get(row)?;
+                // The link's text starts after its `[[`, and the suffix's
+                // parentheses sit either side of the kind.
+                let start = offsets::byte_col(line, range.start.character) + 2 + padding;
+                let suffix = offsets::range(line, row, start + kind.start() - 1, start + kind.end() + 1);
+                Some((kind.as_str().trim().to_string(), suffix))
+            })
+            .collect()
+    }
+
+    /// Every `[[` in the note that doesn't make a usable link, and why: it's
+    /// never closed, has no name, or names something a note can't be called.
+    /// Unknown kinds are left to `unknown_kinds`. A lone `[` is just prose.
+    pub fn malformed_links(&self) -> Vec<(LinkError, Range)> {
+        let content = self.content();
+        let mut malformed = Vec::new();
+        for (row, line) in body_lines(&content) {
+            let mut rest = 0;
+            while let Some(start) = line[rest..].find("[[").map(|i| rest + i) {
+                let inner_start = start + 2;
+                let close = line[inner_start..].find("]]").map(|i| inner_start + i);
+                // Another `[[` before the `]]` means this one was never closed.
+                let reopen = line[inner_start..].find("[[").map(|i| inner_start + i);
+                let end = match (close, reopen) {
+                    (Some(close), Some(reopen)) if reopen < close => None,
+                    (close, _) => close,
+                };
+                let end = match end {
+                    Some(end) => end,
+                    None => {
+                        let until = reopen.unwrap_or(line.trim_end().len());
+                        let until = line[..until].trim_end().len();
+                        malformed.push((LinkError::Unterminated, offsets::range(line, row, start, until)));
+                        rest = reopen.unwrap_or(line.len());
+                        continue;
+                    }
+                };
+                rest = end + 2;
+
+                let inner = &line[inner_start..end];
+                if inner.starts_with("http://") || inner.starts_with("https://") {
+                    continue;
+                }
+                let (note, _) = split_anchor(inner);
+                match NoteID::parse_link(note.trim()) {
+                    Ok(_) | Err(LinkError::UnknownKind(_)) => {}
+                    Err(err) => malformed.push((err, offsets::range(line, row, start, rest))),
+                }
+            }
+        }
+        malformed
+    }
+
+    /// Where the note's title heading (its first `#` heading) starts, or the
+    /// top of the file if it has none.
+    pub fn title_position(&self) -> Position {
+        self.headings()
+            .into_iter()
+            .find(|h| h.level == 1)
+            .map(|h| h.range.start)
+            .unwrap_or(Position::new(0, 0))
+    }
+
+    /// The title to show for this note: its title if it has one, else its
+    /// name.
+    pub fn display_title(&self) -> String {
+        self.title.clone().unwrap_or_else(|| self.id.name.replace('-', " "))
+    }
+
+    /// The opening of the note's body (after the front matter, not counting
+    /// the title heading): its first paragraph, along with any headings
+    /// before it, cut off at `max_lines` lines.
+    pub fn excerpt(&self, max_lines: usize) -> Vec<String> {
+        let content = self.content();
+        let title_row = self
+            .headings()
+            .into_iter()
+            .find(|h| h.level == 1)
+            .map(|h| h.range.start.line as usize);
+
+        let mut lines = Vec::new();
+        let mut in_front_matter = false;
+        let mut fm_started = false;
+
+        for (row, line) in content.lines().enumerate() {
+            let trimmed = line.trim();
+            if !fm_started && trimmed == "---" {
+                in_front_matter = true;
+                fm_started = true;
+                continue;
+            }
+            if in_front_matter {
+                if trimmed == "---" {
+                    in_front_matter = false;
+                }
+                continue;
+            }
+            // Org-style `#+title:` and `#+TODO:` lines are settings, not text.
+            if Some(row) == title_row || trimmed.starts_with("#+") {
+                continue;
+            }
+            if trimmed.is_empty() {
+                // A blank line ends the first paragraph, once we've had one.
+                if lines.iter().any(|l: &String| !l.trim_start().starts_with('#')) {
+                    break;
+                }
+                continue;
+            }
+            if lines.len() == max_lines {
+                break;
+            }
+            lines.push(line.to_string());
+        }
+
+        lines
+    }
+
     /// Parse title and aliases out of a YAML front matter block.
     fn parse_front_matter(content: &str) -> (Option<String>, Vec<String>) {
         let content = content.trim_start();
@@ -202,79 +750,84 @@ impl Note {
     }
 
     pub fn update_links(&mut self) {
-        let content = match self.document.as_ref() {
-            Some(doc) => doc.get_content(None).to_string(),
-            None => return,
-        };
+        let content = self.content().into_owned();
 
         let (title, aliases) = Self::parse_front_matter(&content);
-        self.title = title;
+        self.title = title.or_else(|| title_keyword(&content)).or_else(|| {
+            scan_headings(&content)
+                .into_iter()
+                .find(|(level, _, _)| *level == 1)
+                .map(|(_, text, _)| text)
+        });
         self.aliases = aliases;
+        self.declared_kind = kind_keyword(&content).and_then(|(kind, _)| NoteKind::from_str(&kind));
 
-        let re = Regex::new(r"\[\[([^\]]+)\]\]").unwrap();
-        let mut new_links = Vec::new();
-
-        let mut in_code_block = false;
-        let mut in_front_matter = false;
-        let mut fm_started = false;
-
-        for line in content.lines() {
-            let trimmed = line.trim();
-
-            // Track front matter (skip links inside it)
-            if !fm_started && trimmed == "---" {
-                in_front_matter = true;
-                fm_started = true;
-                continue;
-            }
-            if in_front_matter {
-                if trimmed == "---" {
-                    in_front_matter = false;
-                }
-                continue;
-            }
-
-            // Track fenced code blocks
-            if trimmed.starts_with("```") {
-                in_code_block = !in_code_block;
-                continue;
-            }
-            if in_code_block {
-                continue;
-            }
-
-            for cap in re.captures_iter(line) {
-                let inner = cap.get(1).unwrap().as_str();
-                // Skip external URLs
-                if inner.starts_with("http://") || inner.starts_with("https://") {
-                    continue;
-                }
-                if let Some(id) = NoteID::from_link(inner) {
-                    new_links.push(id);
-                }
+        self.links.clear();
+        for (text, range) in scan_links(&content) {
+            if let Some(target) = LinkTarget::from_link(&text) {
+                self.links.push((target.id, range));
             }
         }
-
         // Also collect #tag outlinks
-        let tag_re = Regex::new(r"#([a-zA-Z][a-zA-Z0-9_\-]*)").unwrap();
-        for line in content.lines() {
-            for cap in tag_re.captures_iter(line) {
-                let tag = cap.get(1).unwrap().as_str();
-                new_links.push(NoteID { name: normalize_name(tag), kind: NoteKind::Any });
-            }
+        self.tags.clear();
+        for (tag, range) in scan_tags(&content) {
+            let name = normalize_name(&tag);
+            self.links.push((NoteID { name: name.clone(), kind: NoteKind::Any }, range));
+            self.tags.insert(name);
         }
 
-        self.outlinks.clear();
-        for id in new_links {
-            self.outlinks.insert(id);
+        self.outlinks = self.links.iter().map(|(id, _)| id.clone()).collect();
+    }
+}
+
+/// The Levenshtein distance between two strings, in characters, or `None`
+/// if it's more than `max`.
+fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitute.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        // Every path to the end goes through this row, so it can't get lower.
+        if current.iter().min().is_some_and(|&least| least > max) {
+            return None;
         }
+        previous = current;
     }
+    Some(previous[b.len()]).filter(|&distance| distance <= max)
+}
+
+/// The title set by an org-style `#+title:` line, if there is one.
+fn title_keyword(content: &str) -> Option<String> {
+    body_lines(content).into_iter().find_map(|(_, line)| {
+        let line = line.trim();
+        let title = line.get(..8).filter(|k| k.eq_ignore_ascii_case("#+title:")).map(|_| line[8..].trim())?;
+        Some(title.to_string()).filter(|t| !t.is_empty())
+    })
+}
+
+/// The kind declared by an org-style `#+kind:` line, if there is one, with
+/// the line's range.
+pub fn kind_keyword(content: &str) -> Option<(String, Range)> {
+    body_lines(content).into_iter().find_map(|(row, line)| {
+        let trimmed = line.trim();
+        let kind =
+            trimmed.get(..7).filter(|k| k.eq_ignore_ascii_case("#+kind:")).map(|_| trimmed[7..].trim())?;
+        let start = line.len() - line.trim_start().len();
+        Some((kind.to_string(), offsets::range(line, row, start, start + trimmed.len())))
+    })
 }
 
 /// Scan `content` for all `#tag` references (outside front matter and code blocks).
 /// Returns `(tag_name_without_hash, lsp_range)` for each match.
 pub fn scan_tags(content: &str) -> Vec<(String, Range)> {
-    let re = Regex::new(r"#([a-zA-Z][a-zA-Z0-9_\-]*)").unwrap();
+    let re = &*TAG_RE;
     let mut results = Vec::new();
 
     let mut in_code_block = false;
@@ -288,23 +841,257 @@ pub fn scan_tags(content: &str) -> Vec<(String, Range)> {
         if trimmed.starts_with("```") { in_code_block = !in_code_block; continue; }
         if in_code_block { continue; }
 
+        // A `#` inside a link is its anchor, not a tag.
+        let links: Vec<_> = LINK_RE.find_iter(line).map(|m| m.range()).collect();
         for cap in re.captures_iter(line) {
             let full = cap.get(0).unwrap();
+            if links.iter().any(|link| link.contains(&full.start())) {
+                continue;
+            }
             let name = cap.get(1).unwrap().as_str();
-            results.push((name.to_string(), Range::new(
-                Position::new(row as u32, full.start() as u32),
-                Position::new(row as u32, full.end() as u32),
-            )));
+            results.push((name.to_string(), offsets::range(line, row, full.start(), full.end())));
+        }
+    }
+
+    results
+}
+
+/// Scan `content` for all ATX `# heading` lines (outside front matter and code blocks).
+/// Returns `(level, heading_text, lsp_range)` for each heading, where the range
+/// covers the whole heading line.
+pub fn scan_headings(content: &str) -> Vec<(usize, String, Range)> {
+    let re = &*HEADING_RE;
+    let mut results = Vec::new();
+
+    let mut in_code_block = false;
+    let mut in_front_matter = false;
+    let mut fm_started = false;
+
+    for (row, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+
+        if !fm_started && trimmed == "---" {
+            in_front_matter = true;
+            fm_started = true;
+            continue;
+        }
+        if in_front_matter {
+            if trimmed == "---" {
+                in_front_matter = false;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+
+        if let Some(cap) = re.captures(line) {
+            let level = cap.get(1).unwrap().as_str().len();
+            let text = cap.get(2).unwrap().as_str();
+            let range = offsets::range(line, row, 0, line.len());
+            results.push((level, text.to_string(), range));
         }
     }
 
     results
 }
 
+/// The task keywords a note uses, split into open and done states. Like
+/// org-mode, a `#+TODO: TODO NEXT | DONE CANCELLED` line sets them, where
+/// keywords after the `|` (or just the last one, without a `|`) are done.
+/// Defaults to `TODO` and `DONE`.
+pub fn todo_keywords(content: &str) -> (Vec<String>, Vec<String>) {
+    let setting = content
+        .lines()
+        .find_map(|line| {
+            let line = line.trim();
+            line.get(..7).filter(|k| k.eq_ignore_ascii_case("#+TODO:")).map(|_| &line[7..])
+        })
+        .map(|rest| rest.split_whitespace().map(|k| k.to_string()).collect::<Vec<_>>())
+        .filter(|keywords| keywords.len() > 1);
+
+    let mut keywords = match setting {
+        Some(keywords) => keywords,
+        None => return (vec!["TODO".to_string()], vec!["DONE".to_string()]),
+    };
+    match keywords.iter().position(|k| k == "|") {
+        Some(bar) => {
+            let done = keywords.split_off(bar + 1);
+            keywords.pop();
+            (keywords, done)
+        }
+        None => {
+            let done = keywords.pop().into_iter().collect();
+            (keywords, done)
+        }
+    }
+}
+
+/// The strongly connected components of a graph given as adjacency lists,
+/// by Tarjan's algorithm. Each component is sorted.
+fn strongly_connected(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    struct Search<'a> {
+        edges: &'a [Vec<usize>],
+        order: Vec<Option<usize>>,
+        low: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    impl Search<'_> {
+        fn visit(&mut self, node: usize) {
+            self.order[node] = Some(self.next);
+            self.low[node] = self.next;
+            self.next += 1;
+            self.stack.push(node);
+            self.on_stack[node] = true;
+
+            for &next in &self.edges[node] {
+                match self.order[next] {
+                    None => {
+                        self.visit(next);
+                        self.low[node] = self.low[node].min(self.low[next]);
+                    }
+                    Some(order) if self.on_stack[next] => {
+                        self.low[node] = self.low[node].min(order);
+                    }
+                    Some(_) => {}
+                }
+            }
+
+            if Some(self.low[node]) == self.order[node] {
+                let mut component = Vec::new();
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                component.sort();
+                self.components.push(component);
+            }
+        }
+    }
+
+    let mut search = Search {
+        edges,
+        order: vec![None; edges.len()],
+        low: vec![0; edges.len()],
+        on_stack: vec![false; edges.len()],
+        stack: Vec::new(),
+        next: 0,
+        components: Vec::new(),
+    };
+    for node in 0..edges.len() {
+        if search.order[node].is_none() {
+            search.visit(node);
+        }
+    }
+    search.components
+}
+
+/// The lines of `content` outside front matter and fenced code blocks, with
+/// their row numbers.
+pub fn body_lines(content: &str) -> Vec<(usize, &str)> {
+    let mut results = Vec::new();
+
+    let mut in_code_block = false;
+    let mut in_front_matter = false;
+    let mut fm_started = false;
+
+    for (row, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if !fm_started && trimmed == "---" {
+            in_front_matter = true;
+            fm_started = true;
+            continue;
+        }
+        if in_front_matter {
+            if trimmed == "---" {
+                in_front_matter = false;
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") {
+            in_code_block = !in_code_block;
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+        results.push((row, line));
+    }
+
+    results
+}
+
+/// Find the lines that open and close the YAML front matter block, if any.
+pub fn front_matter_lines(content: &str) -> Option<(u32, u32)> {
+    let mut lines = content.lines().enumerate();
+    let (start, _) = lines.find(|(_, line)| !line.trim().is_empty())?;
+    if content.lines().nth(start)?.trim() != "---" {
+        return None;
+    }
+    let (end, _) = lines.find(|(_, line)| line.trim() == "---")?;
+    Some((start as u32, end as u32))
+}
+
+/// Scan `content` for fenced code blocks (outside front matter).
+/// Returns the lines of the opening and closing fences of each block; an
+/// unclosed block runs to the last line.
+pub fn scan_code_blocks(content: &str) -> Vec<(u32, u32)> {
+    let mut results = Vec::new();
+    let front_matter_end = front_matter_lines(content).map(|(_, end)| end as usize);
+
+    let mut open: Option<usize> = None;
+    let mut last = 0;
+    for (row, line) in content.lines().enumerate() {
+        last = row;
+        if front_matter_end.is_some_and(|end| row <= end) {
+            continue;
+        }
+        if line.trim().starts_with("```") {
+            match open.take() {
+                Some(start) => results.push((start as u32, row as u32)),
+                None => open = Some(row),
+            }
+        }
+    }
+    if let Some(start) = open {
+        results.push((start as u32, last as u32));
+    }
+
+    results
+}
+
+/// Scan `content` for block quotes (runs of `>` lines outside front matter
+/// and code blocks). Returns the first and last line of each.
+pub fn scan_block_quotes(content: &str) -> Vec<(u32, u32)> {
+    let mut results: Vec<(u32, u32)> = Vec::new();
+    for (row, line) in body_lines(content) {
+        if !line.trim_start().starts_with('>') {
+            continue;
+        }
+        let row = row as u32;
+        match results.last_mut() {
+            Some((_, end)) if *end + 1 == row => *end = row,
+            _ => results.push((row, row)),
+        }
+    }
+    results
+}
+
 /// Scan `content` for all `[[...]]` links (outside front matter and code blocks).
 /// Returns `(raw_link_text, lsp_range)` for each match.
 pub fn scan_links(content: &str) -> Vec<(String, Range)> {
-    let re = Regex::new(r"\[\[([^\]]+)\]\]").unwrap();
+    let re = &*LINK_RE;
     let mut results = Vec::new();
 
     let mut in_code_block = false;
@@ -339,10 +1126,7 @@ pub fn scan_links(content: &str) -> Vec<(String, Range)> {
             if inner.starts_with("http://") || inner.starts_with("https://") {
                 continue;
             }
-            let range = Range::new(
-                Position::new(row as u32, full.start() as u32),
-                Position::new(row as u32, full.end() as u32),
-            );
+            let range = offsets::range(line, row, full.start(), full.end());
             results.push((inner.to_string(), range));
         }
     }
@@ -351,16 +1135,481 @@ pub fn scan_links(content: &str) -> Vec<(String, Range)> {
 }
 
 pub struct Index {
-    pub root: Box<Path>,
+    /// The vault directories, once known. Without any, only opened documents
+    /// are indexed. Notes are keyed by name and kind alone, so links reach
+    /// across vaults.
+    pub roots: Vec<PathBuf>,
     pub notes: BTreeMap<NoteID, Note>,
+    /// Reverse of every note's `links`: link target (as written) → the notes
+    /// containing that link → the ranges of the link within each note.
+    pub backlinks: BTreeMap<NoteID, BTreeMap<NoteID, Vec<Range>>>,
+    /// Reverse of every note's `tags`: tag → the notes using it.
+    pub tagged: BTreeMap<String, BTreeSet<NoteID>>,
+    /// Every note name in use (normalised, so lowercase) → how many notes,
+    /// of any kind, have it. Kept so that near misses can be looked up
+    /// without going through every note.
+    pub names: BTreeMap<String, usize>,
 }
 
 impl Index {
-    pub fn new(root: &Path) -> Self {
+    pub fn new() -> Self {
         Self {
-            root: Box::from(root),
+            roots: Vec::new(),
             notes: BTreeMap::new(),
+            backlinks: BTreeMap::new(),
+            tagged: BTreeMap::new(),
+            names: BTreeMap::new(),
+        }
+    }
+
+    /// Add a vault directory. Returns false if it was already there.
+    pub fn add_root(&mut self, root: PathBuf) -> bool {
+        if self.roots.contains(&root) {
+            return false;
+        }
+        self.roots.push(root);
+        true
+    }
+
+    /// Remove a vault directory, and drop the notes that came from it (unless
+    /// they're open, or another vault contains them too). Returns the dropped
+    /// notes.
+    pub fn remove_root(&mut self, root: &Path) -> Vec<Note> {
+        self.roots.retain(|r| r != root);
+        let dropped: Vec<NoteID> = self
+            .notes
+            .values()
+            .filter(|n| !n.open)
+            .filter(|n| {
+                n.path
+                    .as_ref()
+                    .is_some_and(|p| p.starts_with(root) && self.root_of(p).is_none())
+            })
+            .map(|n| n.id.clone())
+            .collect();
+        dropped.iter().filter_map(|id| self.remove_note(id)).collect()
+    }
+
+    /// Drop every note that isn't open in the editor, e.g. before rescanning
+    /// the vaults from scratch. Open notes keep their unsaved edits, but have
+    /// their links re-extracted.
+    pub fn forget_closed(&mut self) {
+        let (open, closed): (Vec<&Note>, Vec<&Note>) = self.notes.values().partition(|n| n.open);
+        let open: Vec<NoteID> = open.into_iter().map(|n| n.id.clone()).collect();
+        let closed: Vec<NoteID> = closed.into_iter().map(|n| n.id.clone()).collect();
+        for id in &closed {
+            self.remove_note(id);
+        }
+        for id in &open {
+            self.refresh_links(id);
+        }
+    }
+
+    /// The vault a file is in: the deepest root containing it.
+    pub fn root_of(&self, path: &Path) -> Option<&Path> {
+        self.roots
+            .iter()
+            .filter(|r| path.starts_with(r))
+            .max_by_key(|r| r.components().count())
+            .map(|r| r.as_path())
+    }
+
+    /// Where notes go when nothing says which vault: the first one.
+    pub fn default_root(&self) -> Option<&Path> {
+        self.roots.first().map(|r| r.as_path())
+    }
+
+    /// Insert (or replace) a note, keeping the backlink and tag maps in sync.
+    pub fn insert_note(&mut self, note: Note) {
+        let (old, old_tags) = self
+            .notes
+            .get(&note.id)
+            .map(|n| (n.outlinks.clone(), n.tags.clone()))
+            .unwrap_or_default();
+        self.update_backlinks(&note.id, &old, &note.links);
+        self.update_tagged(&note.id, &old_tags, &note.tags);
+        let name = note.id.name.clone();
+        if self.notes.insert(note.id.clone(), note).is_none() {
+            *self.names.entry(name).or_default() += 1;
+        }
+    }
+
+    /// Create a new note file, starting with a title heading, and index it.
+    /// It goes in the root of the vault containing `near` if given, and the
+    /// default vault otherwise. Never overwrites an existing file or note.
+    pub fn create_note(&mut self, id: NoteID, near: Option<&Path>) -> std::io::Result<PathBuf> {
+        let content = format!("# {}\n", id.name.replace('-', " "));
+        self.create_note_with(id, content, near)
+    }
+
+    /// Create a new note file with the given content, and index it. See
+    /// `create_note` for where it goes.
+    pub fn create_note_with(
+        &mut self,
+        id: NoteID,
+        content: String,
+        near: Option<&Path>,
+    ) -> std::io::Result<PathBuf> {
+        use std::io::Write;
+
+        let root = near
+            .and_then(|p| self.root_of(p))
+            .or_else(|| self.default_root())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no vault root is set"))?;
+        if self.notes.contains_key(&id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "a note with this name is already indexed",
+            ));
+        }
+        let path = root.join(id.to_filename());
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?
+            .write_all(content.as_bytes())?;
+
+        let mut note = Note::new(id);
+        note.path = Some(path.clone());
+        note.document = Some(FullTextDocument::new(String::from("noteboks"), 0, content));
+        note.update_links();
+        self.insert_note(note);
+
+        Ok(path)
+    }
+
+    /// The ID of the daily (dump) note for a date, named using `format`.
+    pub fn daily_note_id(date: Date, format: &str) -> NoteID {
+        NoteID { name: normalize_name(&date.format(format)), kind: NoteKind::Dump }
+    }
+
+    /// The daily note for the day before `date`, if there is one.
+    pub fn previous_daily_note(&self, date: Date, format: &str) -> Option<&Note> {
+        self.notes.get(&Self::daily_note_id(date.yesterday(), format))
+    }
+
+    /// The path of the daily note for `date`, creating it if it doesn't exist.
+    /// New daily notes use `template` (with `{{date}}` replaced) if given, and
+    /// start with a link back to the previous day's note if there is one.
+    pub fn open_daily_note(
+        &mut self,
+        date: Date,
+        format: &str,
+        template: Option<&str>,
+    ) -> std::io::Result<PathBuf> {
+        let id = Self::daily_note_id(date, format);
+        if let Some(path) = self.notes.get(&id).and_then(|n| self.path_of(n)) {
+            return Ok(path);
+        }
+
+        let title = date.format(format);
+        let mut content = match self.previous_daily_note(date, format) {
+            Some(previous) => format!("Previous: {}\n\n", previous.id.to_link()),
+            None => String::new(),
+        };
+        match template {
+            Some(template) => content.push_str(&template.replace("{{date}}", &title)),
+            None => content.push_str(&format!("# {}\n", title)),
+        }
+        self.create_note_with(id, content, None)
+    }
+
+    /// The indexed note for `id`, adding an empty one if there isn't one yet.
+    /// It goes in through `insert_note`, so its name is counted.
+    fn note_or_new(&mut self, id: &NoteID) -> &mut Note {
+        if !self.notes.contains_key(id) {
+            self.insert_note(Note::new(id.clone()));
+        }
+        self.notes.get_mut(id).expect("the note was just inserted")
+    }
+
+    /// Drop a note from the index, e.g. because its file was deleted.
+    pub fn remove_note(&mut self, id: &NoteID) -> Option<Note> {
+        let note = self.notes.remove(id)?;
+        self.update_backlinks(id, &note.outlinks, &[]);
+        self.update_tagged(id, &note.tags, &HashSet::new());
+        if let Some(count) = self.names.get_mut(&id.name) {
+            *count -= 1;
+            if *count == 0 {
+                self.names.remove(&id.name);
+            }
         }
+        Some(note)
+    }
+
+    /// Move a note to a new ID and path, e.g. after a rename.
+    pub fn rename_note(&mut self, old: &NoteID, new: NoteID, path: PathBuf) {
+        let mut note = match self.remove_note(old) {
+            Some(note) => note,
+            None => return,
+        };
+
+        note.id = new;
+        note.path = Some(path);
+        note.update_links();
+        self.insert_note(note);
+    }
+
+    /// Re-extract the links of an indexed note after its document changed.
+    /// Returns whether the set of notes it links to changed.
+    pub fn refresh_links(&mut self, id: &NoteID) -> bool {
+        let note = match self.notes.get_mut(id) {
+            Some(note) => note,
+            None => return false,
+        };
+        let old = std::mem::take(&mut note.outlinks);
+        let old_tags = std::mem::take(&mut note.tags);
+        note.update_links();
+        let new = note.links.clone();
+        let new_tags = note.tags.clone();
+        let changed = old != note.outlinks;
+        self.update_backlinks(id, &old, &new);
+        self.update_tagged(id, &old_tags, &new_tags);
+        changed
+    }
+
+    /// Move `source` from the tag map entries for `old` to those for `new`.
+    fn update_tagged(&mut self, source: &NoteID, old: &HashSet<String>, new: &HashSet<String>) {
+        for tag in old.difference(new) {
+            if let Some(notes) = self.tagged.get_mut(tag) {
+                notes.remove(source);
+                if notes.is_empty() {
+                    self.tagged.remove(tag);
+                }
+            }
+        }
+        for tag in new.difference(old) {
+            self.tagged.entry(tag.clone()).or_default().insert(source.clone());
+        }
+    }
+
+    /// The names of existing notes a few edits away from the one `id` names,
+    /// nearest first, for when a link is misspelt. If `id` has a kind, only
+    /// names with a note of that kind count. Short names allow fewer edits,
+    /// so they don't match everything.
+    pub fn closest_names(&self, id: &NoteID, limit: usize) -> Vec<&str> {
+        let max = (id.name.chars().count() / 3).clamp(1, 3);
+        let mut found: Vec<(usize, &str)> = self
+            .names
+            .keys()
+            .filter(|name| **name != id.name)
+            .filter(|name| {
+                id.kind == NoteKind::Any
+                    || self.notes.contains_key(&NoteID { name: name.to_string(), kind: id.kind.clone() })
+            })
+            .filter_map(|name| Some((edit_distance(&id.name, name, max)?, name.as_str())))
+            .collect();
+        found.sort();
+        found.into_iter().take(limit).map(|(_, name)| name).collect()
+    }
+
+    /// Every tag used anywhere in the vault, in order.
+    pub fn all_tags(&self) -> impl Iterator<Item = &str> {
+        self.tagged.keys().map(|t| t.as_str())
+    }
+
+    /// Every note using `#tag` (given with or without the `#`).
+    pub fn notes_with_tag(&self, tag: &str) -> BTreeSet<NoteID> {
+        let tag = normalize_name(tag.trim_start_matches('#'));
+        self.tagged.get(&tag).cloned().unwrap_or_default()
+    }
+
+    /// Drop every backlink entry from `source` to the targets in `old`, then
+    /// record the links in `new`. Ranges shift on every edit, so entries are
+    /// always rebuilt rather than diffed.
+    fn update_backlinks(&mut self, source: &NoteID, old: &BTreeSet<NoteID>, new: &[(NoteID, Range)]) {
+        for target in old {
+            if let Some(sources) = self.backlinks.get_mut(target) {
+                sources.remove(source);
+                if sources.is_empty() {
+                    self.backlinks.remove(target);
+                }
+            }
+        }
+        for (target, range) in new {
+            self.backlinks
+                .entry(target.clone())
+                .or_default()
+                .entry(source.clone())
+                .or_default()
+                .push(*range);
+        }
+    }
+
+    /// The names a note can be linked by: its own, and its aliases if it's
+    /// indexed.
+    pub fn link_names(&self, id: &NoteID) -> Vec<String> {
+        let mut names = vec![id.name.clone()];
+        if let Some(note) = self.notes.get(id) {
+            names.extend(note.aliases.iter().map(|a| normalize_name(a)));
+        }
+        names
+    }
+
+    /// Every note with a link by one of `names`, whether or not it resolves.
+    /// These are the notes a note with those names coming or going affects.
+    pub fn linking_by_name(&self, names: &[String]) -> BTreeSet<NoteID> {
+        self.backlinks
+            .iter()
+            .filter(|(link, _)| names.contains(&link.name))
+            .flat_map(|(_, from)| from.keys().cloned())
+            .collect()
+    }
+
+    /// Every link or tag that resolves to `target`, grouped by the note it
+    /// appears in.
+    pub fn backlinks_to(&self, target: &NoteID) -> BTreeMap<NoteID, Vec<Range>> {
+        let names = self.link_names(target);
+
+        let mut sources: BTreeMap<NoteID, Vec<Range>> = BTreeMap::new();
+        for (link, from) in &self.backlinks {
+            if !names.contains(&link.name) {
+                continue;
+            }
+            if self.resolve_link(link).map(|n| &n.id) != Some(target) {
+                continue;
+            }
+            for (source, ranges) in from {
+                sources.entry(source.clone()).or_default().extend(ranges);
+            }
+        }
+        for ranges in sources.values_mut() {
+            ranges.sort_by_key(|r| (r.start.line, r.start.character));
+        }
+        sources
+    }
+
+    /// Every note no other note links to. Index notes are left out, since
+    /// they're entry points by design.
+    pub fn orphans(&self) -> Vec<NoteID> {
+        let mut linked = HashSet::new();
+        for (link, from) in &self.backlinks {
+            if let Some(target) = self.resolve_link(link)
+                && from.keys().any(|source| *source != target.id)
+            {
+                linked.insert(&target.id);
+            }
+        }
+        self.notes
+            .keys()
+            .filter(|id| id.kind != NoteKind::Index && !linked.contains(id))
+            .cloned()
+            .collect()
+    }
+
+    /// The shortest chain of links from one note to another, both ends
+    /// included, or `None` if links don't connect them. With `undirected`,
+    /// links can be followed backwards too, through the backlinks.
+    pub fn path_between(&self, from: &NoteID, to: &NoteID, undirected: bool) -> Option<Vec<NoteID>> {
+        if !self.notes.contains_key(from) || !self.notes.contains_key(to) {
+            return None;
+        }
+
+        let mut came_from: HashMap<NoteID, NoteID> = HashMap::new();
+        let mut queue = std::collections::VecDeque::from([from.clone()]);
+        while let Some(id) = queue.pop_front() {
+            if id == *to {
+                let mut path = vec![id];
+                while let Some(previous) = path.last().and_then(|last| came_from.get(last)) {
+                    path.push(previous.clone());
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            let note = match self.notes.get(&id) {
+                Some(note) => note,
+                None => continue,
+            };
+            let mut next: Vec<NoteID> = note
+                .outlinks
+                .iter()
+                .filter_map(|link| self.resolve_link(link))
+                .map(|target| target.id.clone())
+                .collect();
+            if undirected {
+                next.extend(self.backlinks_to(&id).into_keys());
+            }
+            for target in next {
+                if target != *from && !came_from.contains_key(&target) {
+                    came_from.insert(target.clone(), id.clone());
+                    queue.push_back(target);
+                }
+            }
+        }
+        None
+    }
+
+    /// The cycles in the link graph. For each group of notes that can all
+    /// reach one another by following links, this gives one cycle through
+    /// the first of them, in link order. Every note that links to itself is
+    /// also a cycle of its own.
+    pub fn cycles(&self) -> Vec<Vec<NoteID>> {
+        let ids: Vec<&NoteID> = self.notes.keys().collect();
+        let position: HashMap<&NoteID, usize> = ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+        let edges: Vec<Vec<usize>> = self
+            .notes
+            .values()
+            .map(|note| {
+                let mut targets: Vec<usize> = note
+                    .outlinks
+                    .iter()
+                    .filter_map(|link| self.resolve_link(link))
+                    .filter_map(|target| position.get(&target.id).copied())
+                    .collect();
+                targets.sort();
+                targets.dedup();
+                targets
+            })
+            .collect();
+
+        let mut cycles = Vec::new();
+        for component in strongly_connected(&edges) {
+            for &node in &component {
+                if edges[node].contains(&node) {
+                    cycles.push(vec![ids[node].clone()]);
+                }
+            }
+            if component.len() == 1 {
+                continue;
+            }
+            let start = component[0];
+
+            // Breadth-first from the start, within the group, until a link
+            // leads back to it; the shortest way round is the cycle.
+            let mut came_from: HashMap<usize, usize> = HashMap::new();
+            let mut queue = std::collections::VecDeque::from([start]);
+            let mut last = start;
+            'search: while let Some(node) = queue.pop_front() {
+                for &next in &edges[node] {
+                    if next == start && node != start {
+                        last = node;
+                        break 'search;
+                    }
+                    if component.contains(&next) && !came_from.contains_key(&next) {
+                        came_from.insert(next, node);
+                        queue.push_back(next);
+                    }
+                }
+            }
+
+            let mut cycle = vec![ids[last].clone()];
+            while last != start {
+                last = came_from[&last];
+                cycle.push(ids[last].clone());
+            }
+            cycle.reverse();
+            cycles.push(cycle);
+        }
+        cycles
+    }
+
+    /// The file a note lives in, or would live in if it's never been saved.
+    pub fn path_of(&self, note: &Note) -> Option<PathBuf> {
+        note.path
+            .clone()
+            .or_else(|| self.default_root().map(|r| r.join(note.id.to_filename())))
     }
 
     pub fn note_at_uri(&self, uri: &Url) -> Option<&Note> {
@@ -373,70 +1622,387 @@ impl Index {
         self.notes.get_mut(&id)
     }
 
-    /// Resolve a link ID to a note, handling Any-kind fallback and aliases.
-    ///
-    /// Resolution order:
-    ///   1. Exact name + kind match (skipped when kind is Any)
-    ///   2. For Any: try Index, Note, Article, List, Person in that order
-    ///   3. Alias search (normalised), respecting kind constraint if present
+    /// Names shared by notes of more than one kind, with the kinds they exist as.
+    pub fn ambiguous_names(&self) -> BTreeMap<String, Vec<NoteKind>> {
+        let mut kinds: BTreeMap<String, Vec<NoteKind>> = BTreeMap::new();
+        for id in self.notes.keys() {
+            kinds.entry(id.name.clone()).or_default().push(id.kind.clone());
+        }
+        kinds.retain(|_, kinds| kinds.len() > 1);
+        kinds
+    }
+
+    /// The note a link points at: the first of `resolve_candidates`.
     pub fn resolve_link(&self, id: &NoteID) -> Option<&Note> {
-        if id.kind != NoteKind::Any {
-            if let Some(note) = self.notes.get(id) {
-                return Some(note);
-            }
-        } else {
-            for kind in &[
-                NoteKind::Index,
-                NoteKind::Note,
-                NoteKind::Article,
-                NoteKind::List,
-                NoteKind::Person,
-            ] {
-                let candidate = NoteID { name: id.name.clone(), kind: kind.clone() };
-                if let Some(note) = self.notes.get(&candidate) {
-                    return Some(note);
-                }
+        self.resolve_candidates(id).into_iter().next()
+    }
+
+    /// Every note a link could point at, best first:
+    ///
+    /// 1. With a kind, as in `[[recipes (list)]]`, just the note with that
+    ///    name and kind. There's no falling back to other kinds, since the
+    ///    kind was asked for.
+    /// 2. Without one, every note with that name, index notes first, then
+    ///    notes, then the other built-in kinds, then custom kinds. More than
+    ///    one means the link is ambiguous.
+    /// 3. Failing those, notes with the name as an alias (of the right kind,
+    ///    if one was given).
+    pub fn resolve_candidates(&self, id: &NoteID) -> Vec<&Note> {
+        let kinds = match id.kind {
+            NoteKind::Any => {
+                let builtin = [
+                    NoteKind::Index,
+                    NoteKind::Note,
+                    NoteKind::Article,
+                    NoteKind::List,
+                    NoteKind::Person,
+                    NoteKind::Dump,
+                ];
+                let custom = NoteKind::all().into_iter().filter(|k| matches!(k, NoteKind::Custom(_)));
+                builtin.into_iter().chain(custom).collect()
             }
+            _ => vec![id.kind.clone()],
+        };
+        let named: Vec<&Note> = kinds
+            .into_iter()
+            .filter_map(|kind| self.notes.get(&NoteID { name: id.name.clone(), kind }))
+            .collect();
+        if !named.is_empty() {
+            return named;
         }
 
-        // Alias fallback
-        let want_kind_any = id.kind == NoteKind::Any;
-        for note in self.notes.values() {
-            if !want_kind_any && note.id.kind != id.kind {
-                continue;
-            }
-            if note.aliases.iter().any(|a| normalize_name(a) == id.name) {
-                return Some(note);
-            }
+        self.notes
+            .values()
+            .filter(|note| id.kind == NoteKind::Any || note.id.kind == id.kind)
+            .filter(|note| note.aliases.iter().any(|a| normalize_name(a) == id.name))
+            .collect()
+    }
+
+    /// Every `[[link]]` in a note that doesn't resolve to an indexed note.
+    /// Tags are left out: a tag without a note of its own is perfectly normal.
+    pub fn broken_links(&self, note: &Note) -> Vec<(NoteID, Range)> {
+        scan_links(&note.content())
+            .into_iter()
+            .filter_map(|(text, range)| LinkTarget::from_link(&text).map(|t| (t.id, range)))
+            .filter(|(id, _)| self.resolve_link(id).is_none())
+            .collect()
+    }
+
+    /// Every `[[note#heading]]` link in a note whose note exists but doesn't
+    /// have that heading. Returns the target note's ID and the anchor.
+    pub fn broken_anchors(&self, note: &Note) -> Vec<(NoteID, String, Range)> {
+        scan_links(&note.content())
+            .into_iter()
+            .filter_map(|(text, range)| {
+                let target = LinkTarget::from_link(&text)?;
+                let anchor = target.anchor?;
+                let linked = self.resolve_link(&target.id)?;
+                match linked.heading(&anchor) {
+                    Some(_) => None,
+                    None => Some((linked.id.clone(), anchor, range)),
+                }
+            })
+            .collect()
+    }
+
+    /// Every `[[link]]` in a note that could point at more than one note, with
+    /// the notes it could mean, best first.
+    pub fn ambiguous_links(&self, note: &Note) -> Vec<(NoteID, Range, Vec<NoteID>)> {
+        scan_links(&note.content())
+            .into_iter()
+            .filter_map(|(text, range)| {
+                let id = LinkTarget::from_link(&text)?.id;
+                let candidates = self.resolve_candidates(&id);
+                if candidates.len() < 2 {
+                    return None;
+                }
+                let candidates = candidates.into_iter().map(|n| n.id.clone()).collect();
+                Some((id, range, candidates))
+            })
+            .collect()
+    }
+
+    /// Attach an opened document to its note, indexing the note if the scan
+    /// hasn't seen it. The editor's buffer always replaces whatever was read
+    /// from disk. Returns whether the indexed content changed, so the caller
+    /// knows whether diagnostics need refreshing.
+    pub fn handle_open(&mut self, document: TextDocumentItem) -> bool {
+        let id = match NoteID::from_uri(&document.uri) {
+            Some(id) => id,
+            None => return false,
+        };
+        let is_new = !self.notes.contains_key(&id);
+        let note = self.note_or_new(&id);
+        if note.path.is_none() {
+            note.path = document.uri.to_file_path().ok();
+        }
+        let unchanged = note
+            .document
+            .as_ref()
+            .is_some_and(|doc| doc.get_content(None) == document.text);
+        note.open = true;
+        note.document = Some(FullTextDocument::new(
+            document.language_id,
+            document.version,
+            document.text,
+        ));
+        if unchanged && !is_new {
+            return false;
         }
+        self.refresh_links(&id);
+        true
+    }
 
-        None
+    /// Forget an editor buffer that's been closed: the note stays indexed, but
+    /// its content is reloaded from what's saved on disk. If there's nothing on
+    /// disk any more, the note is dropped; returns whether that happened.
+    pub fn handle_close(&mut self, uri: &Url) -> bool {
+        let id = match NoteID::from_uri(uri) {
+            Some(id) => id,
+            None => return false,
+        };
+        match uri.to_file_path().ok().and_then(|p| Note::of_file(&p)) {
+            Some(note) => {
+                self.insert_note(note);
+                false
+            }
+            None => self.remove_note(&id).is_some(),
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn handle_open(&mut self, document: TextDocumentItem) {
-        let uri = document.uri.clone();
-        let doc = FullTextDocument::new(document.language_id, document.version, document.text);
-        if let Some(id) = NoteID::from_uri(&uri) {
-            let note = self.notes.entry(id.clone()).or_insert_with(|| Note::new(id));
-            note.document = Some(doc);
-            note.update_links();
+    /// Bring a saved note in line with what was written: the saved text if the
+    /// client sent it, otherwise the file on disk. Either way the note's links
+    /// are re-extracted, since a formatter may have rewritten it on save.
+    pub fn handle_save(&mut self, uri: &Url, text: Option<String>) {
+        let id = match NoteID::from_uri(uri) {
+            Some(id) => id,
+            None => return,
+        };
+        let text = match text {
+            Some(text) => text,
+            None => {
+                if let Some(mut note) = uri.to_file_path().ok().and_then(|p| Note::of_file(&p)) {
+                    // Only open documents get saved.
+                    note.open = true;
+                    self.insert_note(note);
+                }
+                return;
+            }
+        };
+
+        let note = self.note_or_new(&id);
+        if note.path.is_none() {
+            note.path = uri.to_file_path().ok();
         }
+        note.open = true;
+        let stale = note.document.as_ref().is_none_or(|doc| doc.get_content(None) != text);
+        if stale {
+            let version = note.document.as_ref().map_or(0, |doc| doc.version());
+            note.document = Some(FullTextDocument::new(String::from("noteboks"), version, text));
+        }
+        self.refresh_links(&id);
     }
 
+    /// Apply an editor change to a note's text, returning the note's ID. Its
+    /// links aren't re-extracted; call `refresh_links` for that.
     pub fn handle_edit(
         &mut self,
         document: VersionedTextDocumentIdentifier,
         changes: Vec<TextDocumentContentChangeEvent>,
-    ) {
+    ) -> Option<NoteID> {
+        // tower-lsp and lsp-textdocument use different versions of the same
+        // types, so convert through JSON.
         let changes_: Vec<lsp_types::TextDocumentContentChangeEvent> =
-            serde_json::from_value(serde_json::to_value(changes).unwrap()).unwrap();
+            serde_json::to_value(changes).and_then(serde_json::from_value).ok()?;
 
-        if let Some(note) = self.note_at_uri_mut(&document.uri) {
-            if let Some(doc) = note.document.as_mut() {
-                doc.update(&changes_, document.version);
-            }
+        let note = self.note_at_uri_mut(&document.uri)?;
+        if let Some(doc) = note.document.as_mut() {
+            doc.update(&changes_, document.version);
+        }
+        Some(note.id.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(name: &str, kind: NoteKind) -> NoteID {
+        NoteID { name: name.to_string(), kind }
+    }
+
+    fn note(name: &str, content: &str) -> Note {
+        let mut note = Note::new(id(name, NoteKind::Note));
+        note.document = Some(FullTextDocument::new(String::from("noteboks"), 0, content.to_string()));
+        note.update_links();
+        note
+    }
+
+    #[test]
+    fn parse_link_names() {
+        assert_eq!(NoteID::parse_link("lambda"), Ok(id("lambda", NoteKind::Any)));
+        assert_eq!(NoteID::parse_link("Lambda  Calculus"), Ok(id("lambda-calculus", NoteKind::Any)));
+        assert_eq!(NoteID::parse_link("snake_case name"), Ok(id("snake-case-name", NoteKind::Any)));
+        assert_eq!(NoteID::parse_link("either/or"), Ok(id("either/or", NoteKind::Any)));
+        assert_eq!(NoteID::parse_link("what? why:"), Ok(id("what?-why:", NoteKind::Any)));
+    }
+
+    #[test]
+    fn parse_link_kinds() {
+        assert_eq!(NoteID::parse_link("modal logic (index)"), Ok(id("modal-logic", NoteKind::Index)));
+        assert_eq!(NoteID::parse_link("modal logic(Article)"), Ok(id("modal-logic", NoteKind::Article)));
+        assert_eq!(NoteID::parse_link("modal logic ( person )"), Ok(id("modal-logic", NoteKind::Person)));
+        assert_eq!(NoteID::parse_link("projects/ideas.list"), Ok(id("ideas", NoteKind::List)));
+    }
+
+    #[test]
+    fn parse_link_errors() {
+        assert_eq!(NoteID::parse_link(""), Err(LinkError::Empty));
+        assert_eq!(NoteID::parse_link("   "), Err(LinkError::Empty));
+        assert_eq!(NoteID::parse_link(" (note)"), Err(LinkError::Empty));
+        assert_eq!(NoteID::parse_link("a*b"), Err(LinkError::InvalidCharacter('*')));
+        assert_eq!(NoteID::parse_link("a.b"), Err(LinkError::InvalidCharacter('.')));
+        assert_eq!(
+            NoteID::parse_link("modal logic (artcle)"),
+            Err(LinkError::UnknownKind("artcle".to_string()))
+        );
+    }
+
+    #[test]
+    fn unterminated_links() {
+        let note = note("a", "fine [[b]]\nbroken [[c\n");
+        let malformed: Vec<LinkError> = note.malformed_links().into_iter().map(|(err, _)| err).collect();
+        assert_eq!(malformed, vec![LinkError::Unterminated]);
+    }
+
+    #[test]
+    fn link_text_round_trips() {
+        let path = NoteID::parse_link("projects/reading list.list").unwrap();
+        for id in [id("lambda-calculus", NoteKind::Any), id("modal-logic", NoteKind::Index), path] {
+            let text = link_text(&id.name.replace('-', " "), &id.kind);
+            assert_eq!(NoteID::parse_link(&text), Ok(id.clone()));
+            assert_eq!(id.to_link(), format!("[[{}]]", text));
+        }
+        assert_eq!(link_text("modal logic", &NoteKind::Index), "modal logic (index)");
+        assert_eq!(link_text("lambda", &NoteKind::Any), "lambda");
+    }
+
+    #[test]
+    fn notes_with_tag() {
+        let mut index = Index::new();
+        index.insert_note(note("a", "# A\nAbout #Rust and #lsp.\n"));
+        index.insert_note(note("b", "# B\nMore #rust.\n"));
+        let (a, b) = (id("a", NoteKind::Note), id("b", NoteKind::Note));
+
+        assert_eq!(index.notes_with_tag("rust"), BTreeSet::from([a.clone(), b.clone()]));
+        assert_eq!(index.notes_with_tag("#LSP"), BTreeSet::from([a.clone()]));
+        assert!(index.notes_with_tag("go").is_empty());
+
+        // Dropping a tag from the text takes the note out of its entry.
+        let text = String::from("# A\nAbout #rust.\n");
+        index.notes.get_mut(&a).unwrap().document =
+            Some(FullTextDocument::new(String::from("noteboks"), 1, text));
+        index.refresh_links(&a);
+        assert!(index.notes_with_tag("lsp").is_empty());
+        assert_eq!(index.notes_with_tag("rust"), BTreeSet::from([a.clone(), b.clone()]));
+
+        index.remove_note(&b);
+        assert_eq!(index.notes_with_tag("rust"), BTreeSet::from([a]));
+    }
+
+    #[test]
+    fn update_links_is_repeatable() {
+        let mut note = note("a", "# A\nSee [[b]], [[c (list)::Intro]] and #tag.\n```\n[[code]]\n```\n");
+        let (links, outlinks, tags) = (note.links.clone(), note.outlinks.clone(), note.tags.clone());
+        assert_eq!(links.len(), 3);
+
+        for _ in 0..100 {
             note.update_links();
+            assert_eq!(note.links, links);
+            assert_eq!(note.outlinks, outlinks);
+            assert_eq!(note.tags, tags);
+        }
+    }
+
+    #[test]
+    fn orphans_are_in_a_stable_order() {
+        let notes = [
+            ("zeta", "# Zeta\n[[beta]] [[alpha]]\n"),
+            ("alpha", "# Alpha\n[[alpha]]\n"),
+            ("mu", "# Mu\n"),
+            ("beta", "# Beta\n"),
+            ("delta", "# Delta\n[[zeta]] [[beta]]\n"),
+            ("gamma", "# Gamma\n"),
+        ];
+        let mut forwards = Index::new();
+        for (name, content) in notes {
+            forwards.insert_note(note(name, content));
+        }
+        let mut backwards = Index::new();
+        for (name, content) in notes.into_iter().rev() {
+            backwards.insert_note(note(name, content));
         }
+
+        let orphans = forwards.orphans();
+        let names: Vec<&str> = orphans.iter().map(|id| id.name.as_str()).collect();
+        assert_eq!(names, ["delta", "gamma", "mu"]);
+        assert_eq!(backwards.orphans(), orphans);
+
+        // Outlinks come out sorted, whatever order they're written in.
+        let zeta = &forwards.notes[&id("zeta", NoteKind::Note)];
+        let outlinks: Vec<&str> = zeta.outlinks.iter().map(|id| id.name.as_str()).collect();
+        assert_eq!(outlinks, ["alpha", "beta"]);
+    }
+
+    #[test]
+    fn ambiguous_names() {
+        let mut index = Index::new();
+        index.insert_note(note("logic", "# Logic\n"));
+        index.insert_note(Note::new(id("logic", NoteKind::Index)));
+        index.insert_note(note("sets", "# Sets\n"));
+
+        let ambiguous = index.ambiguous_names();
+        assert_eq!(ambiguous.len(), 1);
+        assert_eq!(ambiguous["logic"], [NoteKind::Note, NoteKind::Index]);
+    }
+
+    fn date(year: i64, month: u32, day: u32) -> Date {
+        Date { year, month, day }
+    }
+
+    #[test]
+    fn yesterday_rolls_over() {
+        assert_eq!(date(2024, 3, 15).yesterday(), date(2024, 3, 14));
+        assert_eq!(date(2024, 3, 1).yesterday(), date(2024, 2, 29));
+        assert_eq!(date(2023, 3, 1).yesterday(), date(2023, 2, 28));
+        assert_eq!(date(2024, 5, 1).yesterday(), date(2024, 4, 30));
+        assert_eq!(date(2024, 1, 1).yesterday(), date(2023, 12, 31));
+        assert_eq!(date(1970, 1, 1).yesterday(), date(1969, 12, 31));
+        assert_eq!(Date::from_days(date(2000, 2, 29).to_days()), date(2000, 2, 29));
+    }
+
+    #[test]
+    fn date_formats() {
+        let day = date(2024, 3, 5);
+        assert_eq!(day.format("YYYY-MM-DD"), "2024-03-05");
+        assert_eq!(day.format("DD.MM.YYYY"), "05.03.2024");
+        assert_eq!(day.format("journal YYYY MM"), "journal 2024 03");
+
+        assert_eq!(Index::daily_note_id(day, "YYYY-MM-DD"), id("2024-03-05", NoteKind::Dump));
+        assert_eq!(Index::daily_note_id(day, "Day DD_MM"), id("day-05-03", NoteKind::Dump));
+    }
+
+    #[test]
+    fn previous_daily_note_across_a_year() {
+        let mut index = Index::new();
+        let format = "DD-MM-YYYY";
+        index.insert_note(Note::new(Index::daily_note_id(date(2023, 12, 31), format)));
+        index.insert_note(Note::new(Index::daily_note_id(date(2024, 1, 2), "YYYY-MM-DD")));
+
+        let previous = index.previous_daily_note(date(2024, 1, 1), format).map(|n| &n.id);
+        assert_eq!(previous, Some(&id("31-12-2023", NoteKind::Dump)));
+        // Only the day before counts, and only in the same format.
+        assert!(index.previous_daily_note(date(2024, 1, 2), format).is_none());
+        assert!(index.previous_daily_note(date(2024, 1, 3), format).is_none());
     }
 }
@@ -1,24 +1,126 @@
+mod formatting;
+mod graph;
 mod index;
+mod offsets;
+mod selection;
+mod semantic_tokens;
+mod settings;
 
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
 use regex::Regex;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, RwLock};
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
-use tower_lsp::lsp_types::{notification::Progress, request::WorkDoneProgressCreate};
+use tower_lsp::lsp_types::{
+    notification::Progress,
+    request::{GotoDeclarationParams, GotoDeclarationResponse, WorkDoneProgressCreate},
+};
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 use walkdir::WalkDir;
 
-use crate::index::{scan_links, scan_tags, Index, Note, NoteID, NoteKind};
-
+use crate::graph::Graph;
+use crate::index::{
+    scan_links, Date, Heading, Index, LinkError, LinkTarget, Note, NoteID, NoteKind, Task, LINK_RE, TAG_RE,
+};
+use crate::settings::Settings;
+
+/// The most results a workspace symbol search returns, so that a short query
+/// over a big vault doesn't produce an enormous response.
+const MAX_WORKSPACE_SYMBOLS: usize = 200;
+
+/// The diagnostic code for a link to a note that doesn't exist, which the
+/// "create note" quick fix looks for.
+const NOTE_NOT_FOUND: &str = "note-not-found";
+
+/// Creates a note: takes `{ "name": ..., "kind": ..., "from": ... }` (kind
+/// defaults to `note`) and returns the new file's URI. The note goes in the
+/// same vault as the `from` URI, or the first vault without one.
+const CREATE_NOTE_COMMAND: &str = "noteboks.createNote";
+
+/// Inserts a link: takes a `{ "name": ..., "kind": ... }` target and a text
+/// document position, and applies an edit putting `[[name]]` there.
+const INSERT_LINK_COMMAND: &str = "noteboks.insertLink";
+
+/// Opens today's daily (dump) note, creating it if need be, and returns its URI.
+const OPEN_DAILY_NOTE_COMMAND: &str = "noteboks.openDailyNote";
+
+/// Exports the link graph: takes `{ "format": ..., "path": ...,
+/// "includeBroken": ... }`. With a `format` (`"json"` or `"dot"`) and no
+/// `path`, returns the graph as a string. Otherwise writes it to `path`
+/// (default `graph.dot` in the first vault), as DOT unless the format says
+/// otherwise, and returns the file's URI.
+const EXPORT_GRAPH_COMMAND: &str = "noteboks.exportGraph";
+
+/// Forgets every note that isn't open and reads the vaults again, for when
+/// the index has drifted from what's on disk.
+const RESCAN_COMMAND: &str = "noteboks.rescan";
+
+/// Lists the cycles in the link graph, each as the notes it goes through, in
+/// order. A note that links to itself is a cycle of one.
+const LIST_CYCLES_COMMAND: &str = "noteboks.listCycles";
+
+/// Changes a note's kind: takes `{ "name": ..., "kind": ..., "newKind": ...
+/// }`, renames the file to the new extension and rewrites the `(kind)` of
+/// every link to it. Fails rather than overwrite a note that already exists.
+const CHANGE_KIND_COMMAND: &str = "noteboks.changeKind";
+
+/// Moves the section under a heading into a note of its own: takes `{ "uri":
+/// ..., "line": ... }` for the heading, creates the note, replaces the
+/// section with a link to it, and returns the new file's URI.
+const EXTRACT_SUBTREE_COMMAND: &str = "noteboks.extractSubtree";
+
+/// Finds the shortest chain of links between two notes: takes two `{ "name":
+/// ..., "kind": ... }` arguments, and optionally `{ "undirected": true }` to
+/// follow links backwards as well. Returns the notes along the way, both
+/// ends included, or null if there's no such chain.
+const PATH_BETWEEN_COMMAND: &str = "noteboks.pathBetween";
+
+/// Lists every note nothing else links to, except index notes.
+const LIST_ORPHANS_COMMAND: &str = "noteboks.listOrphans";
+
+/// Another name for `noteboks.listOrphans`.
+const FIND_ORPHANS_COMMAND: &str = "noteboks.findOrphans";
+
+/// Lists every open (not done) task in the vault, with its location.
+const LIST_TASKS_COMMAND: &str = "noteboks.listTasks";
+
+/// Lists where a note is linked from: takes `{ "uri": ... }` for the note and
+/// returns the location of each link to it. The backlink lens runs this.
+const LIST_BACKLINKS_COMMAND: &str = "noteboks.listBacklinks";
+
+/// The ID the file watchers are registered under, so they can be replaced
+/// when the vaults change.
+const WATCHED_FILES_REGISTRATION: &str = "noteboks/watched-files";
+
+/// How long typing has to pause before a note's links and diagnostics are
+/// recomputed.
+const EDIT_DEBOUNCE: Duration = Duration::from_millis(150);
+
+#[derive(Clone)]
 struct Backend {
     client: Client,
-    index: Arc<Mutex<Index>>,
+    /// Requests that only look things up share the index; edits, scans and
+    /// file changes take it exclusively.
+    index: Arc<RwLock<Index>>,
+    settings: Arc<Mutex<Settings>>,
     /// Set to `true` the first time a scan is kicked off, so we never scan twice.
     scan_started: Arc<AtomicBool>,
+    /// Whether the client takes `window/workDoneProgress`, which the scan
+    /// reports its progress with.
+    progress_supported: Arc<AtomicBool>,
+    /// Whether the client lets file watchers be registered while running,
+    /// which is how we hear about notes changing outside the editor.
+    watchers_supported: Arc<AtomicBool>,
+    /// A count of the edits made to each document, so that a debounced
+    /// refresh can tell whether another edit has come in since.
+    edit_counts: Arc<Mutex<HashMap<Url, u64>>>,
 }
 
 impl Backend {
@@ -26,7 +128,7 @@ impl Backend {
     async fn publish_all_diagnostics(&self) {
         let uris: Vec<Url> = self
             .index
-            .lock()
+            .read()
             .await
             .notes
             .values()
@@ -37,173 +139,976 @@ impl Backend {
         }
     }
 
+    /// Push diagnostics for a note and every note linking to it.
+    async fn publish_dependent_diagnostics(&self, id: NoteID) {
+        let names = self.index.read().await.link_names(&id);
+        self.publish_linking_diagnostics(vec![id], &names).await;
+    }
+
+    /// Push diagnostics for the given notes and every note with a link by one
+    /// of `names`, whether or not it resolves. Take the names before removing
+    /// a note, since its aliases go with it.
+    async fn publish_linking_diagnostics(&self, mut ids: Vec<NoteID>, names: &[String]) {
+        ids.extend(self.index.read().await.linking_by_name(names));
+        ids.sort();
+        ids.dedup();
+        self.publish_diagnostics_for(ids).await;
+    }
+
+    /// Push diagnostics for each of the given notes.
+    async fn publish_diagnostics_for(&self, ids: Vec<NoteID>) {
+        for id in ids {
+            let uri = {
+                let index = self.index.read().await;
+                index
+                    .notes
+                    .get(&id)
+                    .and_then(|n| index.path_of(n))
+                    .and_then(|p| Url::from_file_path(p).ok())
+            };
+            if let Some(uri) = uri {
+                self.publish_diagnostics(uri).await;
+            }
+        }
+    }
+
     /// Push diagnostics for a single document to the client.
-    async fn publish_diagnostics(&self, _uri: Url) {
-        // let index = self.index.lock().await;
-        // let diagnostics = if let Some(note) = index.note_at_uri(&uri) {
-        //     if let Some(doc) = &note.document {
-        //         let content = doc.get_content(None);
-        //         scan_links(content)
-        //             .into_iter()
-        //             .filter_map(|(text, range)| {
-        //                 let id = NoteID::from_link(&text)?;
-        //                 if index.resolve_link(&id).is_some() {
-        //                     return None; // link is fine
-        //                 }
-        //                 Some(Diagnostic {
-        //                     range,
-        //                     severity: Some(DiagnosticSeverity::HINT),
-        //                     message: format!("No note found for [[{}]]", text),
-        //                     source: Some("noteboks".to_string()),
-        //                     ..Default::default()
-        //                 })
-        //             })
-        //             .collect()
-        //     } else {
-        //         vec![]
-        //     }
-        // } else {
-        //     vec![]
-        // };
-
-        // self.client
-        //     .publish_diagnostics(uri, diagnostics, None)
-        //     .await;
-    }
-
-    /// Kick off a background vault scan, but only if one hasn't already started.
+    async fn publish_diagnostics(&self, uri: Url) {
+        let diagnostics = {
+            let index = self.index.read().await;
+            match index.note_at_uri(&uri) {
+                Some(note) => note_diagnostics(&index, note),
+                None => vec![],
+            }
+        };
+
+        self.client.publish_diagnostics(uri, diagnostics, None).await;
+    }
+
+    /// Ask to hear about note files in any vault changing outside the editor,
+    /// if the client takes watchers.
+    async fn register_watchers(&self) {
+        if !self.watchers_supported.load(Ordering::SeqCst) {
+            return;
+        }
+        let roots = self.index.read().await.roots.clone();
+        let mut watchers: Vec<FileSystemWatcher> = roots
+            .into_iter()
+            .filter_map(|root| Url::from_directory_path(root).ok())
+            .map(|base| FileSystemWatcher {
+                glob_pattern: GlobPattern::Relative(RelativePattern {
+                    base_uri: OneOf::Right(base),
+                    pattern: note_glob(),
+                }),
+                kind: None,
+            })
+            .collect();
+        if watchers.is_empty() {
+            watchers.push(FileSystemWatcher { glob_pattern: GlobPattern::String(note_glob()), kind: None });
+        }
+
+        let options = DidChangeWatchedFilesRegistrationOptions { watchers };
+        let registration = Registration {
+            id: WATCHED_FILES_REGISTRATION.to_string(),
+            method: "workspace/didChangeWatchedFiles".to_string(),
+            register_options: serde_json::to_value(options).ok(),
+        };
+        let _ = self.client.register_capability(vec![registration]).await;
+    }
+
+    /// Kick off a background scan of every vault, but only if one hasn't
+    /// already started.
     fn trigger_scan(&self) {
         // swap returns the *old* value; if it was already true, someone else started.
         if self.scan_started.swap(true, Ordering::SeqCst) {
             return;
         }
 
-        let client = self.client.clone();
-        let index = self.index.clone();
-
+        let backend = self.clone();
         tokio::spawn(async move {
-            let root = index.lock().await.root.to_path_buf();
+            let roots = backend.index.read().await.roots.clone();
+            backend.scan(roots, false).await;
+            backend.publish_all_diagnostics().await;
+        });
+    }
 
-            // Collect all recognisable note file paths up front.
-            let paths: Vec<PathBuf> = WalkDir::new(&root)
-                .into_iter()
-                .filter_map(|e| e.ok())
-                .filter(|e| e.file_type().is_file())
-                .map(|e| e.path().to_path_buf())
-                .filter(|p| NoteKind::from_path(p).is_some())
-                .collect();
+    /// Send a scan progress update, if the client takes them.
+    async fn progress(&self, token: &NumberOrString, progress: WorkDoneProgress) {
+        if self.progress_supported.load(Ordering::SeqCst) {
+            self.client
+                .send_notification::<Progress>(ProgressParams {
+                    token: token.clone(),
+                    value: ProgressParamsValue::WorkDone(progress),
+                })
+                .await;
+        }
+    }
 
-            let total = paths.len();
-            if total == 0 {
-                return;
-            }
+    /// Index every note under `roots`, reporting progress to the client. With
+    /// `fresh`, closed notes are forgotten and read again from disk.
+    async fn scan(&self, roots: Vec<PathBuf>, fresh: bool) {
+        let client = &self.client;
+        let index = &self.index;
+
+        // Without a vault root there's nothing to scan; opened documents
+        // are still indexed as they arrive.
+        if roots.is_empty() {
+            return;
+        }
 
-            let token = NumberOrString::String("noteboks/indexing".to_string());
+        let token = NumberOrString::String("noteboks/indexing".to_string());
 
-            // Ask the client to create a progress indicator.
+        // Ask the client to create a progress indicator, and start it before
+        // walking the vault, which can itself take a while.
+        if self.progress_supported.load(Ordering::SeqCst) {
             let _ = client
                 .send_request::<WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
                     token: token.clone(),
                 })
                 .await;
+        }
+        self.progress(
+            &token,
+            WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: "Noteboks".to_string(),
+                cancellable: Some(false),
+                message: Some("Finding notes…".to_string()),
+                percentage: Some(0),
+            }),
+        )
+        .await;
+
+        // Collect all recognisable note file paths up front. Walking and
+        // reading block, so they run off the async workers, and progress is
+        // reported between batches.
+        let paths: Vec<PathBuf> = tokio::task::spawn_blocking(move || {
+            roots
+                .iter()
+                .flat_map(|root| WalkDir::new(root).into_iter())
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .map(|e| e.path().to_path_buf())
+                .filter(|p| NoteKind::from_path(p).is_some())
+                .collect()
+        })
+        .await
+        .unwrap_or_default();
+        let total = paths.len();
+
+        // Read every file (pure I/O, no lock needed), 25 at a time.
+        let mut notes = Vec::with_capacity(total);
+        let mut read = 0;
+        for batch in paths.chunks(25) {
+            let batch = batch.to_vec();
+            read += batch.len();
+            let batch_notes = tokio::task::spawn_blocking(move || {
+                batch.iter().filter_map(|path| Note::of_file(path)).collect::<Vec<Note>>()
+            })
+            .await
+            .unwrap_or_default();
+            notes.extend(batch_notes);
+
+            let pct = (read * 100 / total) as u32;
+            self.progress(
+                &token,
+                WorkDoneProgress::Report(WorkDoneProgressReport {
+                    cancellable: Some(false),
+                    message: Some(format!("{}/{}", read, total)),
+                    percentage: Some(pct),
+                }),
+            )
+            .await;
+        }
 
-            // Begin.
-            client
-                .send_notification::<Progress>(ProgressParams {
-                    token: token.clone(),
-                    value: ProgressParamsValue::WorkDone(WorkDoneProgress::Begin(
-                        WorkDoneProgressBegin {
-                            title: "Noteboks".to_string(),
-                            cancellable: Some(false),
-                            message: Some(format!("Indexing {} files…", total)),
-                            percentage: Some(0),
-                        },
-                    )),
+        // Insert all notes into the index in one lock acquisition. Notes
+        // opened while we were scanning keep their editor buffers. A fresh
+        // scan swaps out every closed note in the same go, so nothing looks
+        // missing while the vaults are read; those gone from disk lose their
+        // diagnostics.
+        let indexed = notes.len();
+        let mut gone = Vec::new();
+        let open_diagnostics: Vec<(Url, Vec<Diagnostic>)> = {
+            let mut idx = index.write().await;
+            let mut closed: Vec<(NoteID, Option<PathBuf>)> = Vec::new();
+            if fresh {
+                closed = idx.notes.values().filter(|n| !n.open).map(|n| (n.id.clone(), n.path.clone())).collect();
+                idx.forget_closed();
+            }
+            for note in notes {
+                if !idx.notes.get(&note.id).is_some_and(|n| n.open) {
+                    idx.insert_note(note);
+                }
+            }
+            gone.extend(
+                closed
+                    .into_iter()
+                    .filter(|(id, _)| !idx.notes.contains_key(id))
+                    .filter_map(|(_, path)| Url::from_file_path(path?).ok()),
+            );
+
+            // Links in open notes may have only just become resolvable.
+            idx.notes
+                .values()
+                .filter(|n| n.open)
+                .filter_map(|n| {
+                    let uri = Url::from_file_path(n.path.as_ref()?).ok()?;
+                    Some((uri, note_diagnostics(&idx, n)))
                 })
-                .await;
+                .collect()
+        };
+        for (uri, diagnostics) in open_diagnostics {
+            client.publish_diagnostics(uri, diagnostics, None).await;
+        }
+        for uri in gone {
+            client.publish_diagnostics(uri, vec![], None).await;
+        }
 
-            // Read every file (pure I/O, no lock needed).
-            let mut notes = Vec::with_capacity(total);
-            for (i, path) in paths.iter().enumerate() {
-                if let Some(note) = Note::of_file(path) {
-                    notes.push(note);
-                }
+        // Done.
+        self.progress(
+            &token,
+            WorkDoneProgress::End(WorkDoneProgressEnd {
+                message: Some(format!("{} notes indexed", indexed)),
+            }),
+        )
+        .await;
+    }
 
-                // Send a progress report every 25 files and on the last file.
-                if (i + 1) % 25 == 0 || i + 1 == total {
-                    let pct = ((i + 1) * 100 / total) as u32;
-                    client
-                        .send_notification::<Progress>(ProgressParams {
-                            token: token.clone(),
-                            value: ProgressParamsValue::WorkDone(WorkDoneProgress::Report(
-                                WorkDoneProgressReport {
-                                    cancellable: Some(false),
-                                    message: Some(format!("{}/{}", i + 1, total)),
-                                    percentage: Some(pct),
-                                },
-                            )),
-                        })
-                        .await;
-                }
+    /// `noteboks/graph`: the live link graph as JSON, in the same shape as
+    /// `noteboks.exportGraph` gives. Takes `{ "center": ..., "depth": ... }`
+    /// to return only the notes within `depth` (default 1) links of the note
+    /// `center` links to; `{}` returns the whole vault.
+    async fn graph(&self, params: serde_json::Value) -> Result<serde_json::Value> {
+        let index = self.index.read().await;
+        let graph = Graph::of_index(&index);
+
+        let graph = match params.get("center").and_then(|c| c.as_str()) {
+            Some(center) => {
+                let center = NoteID::from_link(center)
+                    .and_then(|id| index.resolve_link(&id))
+                    .map(|n| n.id.clone())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params(format!("No note named '{}' found", center))
+                    })?;
+                let depth = params.get("depth").and_then(|d| d.as_u64()).unwrap_or(1);
+                graph.neighbourhood(&center, depth as usize)
             }
+            None => graph,
+        };
 
-            // Insert all notes into the index in one lock acquisition.
-            let indexed = notes.len();
-            {
-                let mut idx = index.lock().await;
-                for note in notes {
-                    idx.notes.insert(note.id.clone(), note);
-                }
-            }
+        Ok(graph.to_json(false))
+    }
+}
 
-            // Done.
-            client
-                .send_notification::<Progress>(ProgressParams {
-                    token,
-                    value: ProgressParamsValue::WorkDone(WorkDoneProgress::End(
-                        WorkDoneProgressEnd {
-                            message: Some(format!("{} notes indexed", indexed)),
-                        },
-                    )),
+/// A glob matching every note file, by extension.
+fn note_glob() -> String {
+    let kinds = NoteKind::all();
+    let extensions: Vec<&str> = kinds.iter().map(|k| k.to_str()).collect();
+    format!("**/*.{{{}}}", extensions.join(","))
+}
+
+/// File operation filters matching every note file.
+fn note_file_operations() -> FileOperationRegistrationOptions {
+    FileOperationRegistrationOptions {
+        filters: vec![FileOperationFilter {
+            scheme: Some("file".to_string()),
+            pattern: FileOperationPattern {
+                glob: note_glob(),
+                matches: Some(FileOperationPatternKind::File),
+                // Extensions are matched case-insensitively, like `NoteKind::from_path`.
+                options: Some(FileOperationPatternOptions { ignore_case: Some(true) }),
+            },
+        }],
+    }
+}
+
+/// Whether two ranges share at least one position.
+fn ranges_overlap(a: &Range, b: &Range) -> bool {
+    let key = |p: &Position| (p.line, p.character);
+    key(&a.start) <= key(&b.end) && key(&b.start) <= key(&a.end)
+}
+
+/// Read a `{ "name": ..., "kind": ... }` command argument as a note ID. A
+/// missing kind means `note`. Names that couldn't be linked to, or that
+/// wouldn't make a valid file name, are refused.
+fn note_id_arg(arg: Option<&serde_json::Value>) -> std::result::Result<NoteID, String> {
+    let name = match arg.and_then(|a| a.get("name")).and_then(|n| n.as_str()) {
+        Some(name) => name.trim(),
+        None => return Err("Expected a { name, kind } argument".to_string()),
+    };
+    if let Some(c) = name.chars().find(|c| "/\\:?*\"<>|#[]()".contains(*c)) {
+        return Err(format!("Note names can't contain '{}'", c));
+    }
+    let id = NoteID::parse_link(name)
+        .map_err(|err| format!("'{}' is not a valid note name: {}", name, err))?;
+    let kind = match arg.and_then(|a| a.get("kind")).and_then(|k| k.as_str()) {
+        Some(kind) => match NoteKind::from_str(kind) {
+            Some(kind) => kind,
+            None => return Err(format!("Unknown note kind '{}'", kind)),
+        },
+        None => NoteKind::Note,
+    };
+    Ok(NoteID { name: id.name, kind })
+}
+
+/// Compute the diagnostics for a note: a warning per link that doesn't
+/// resolve (to a note, or to a heading within one) or that could mean more
+/// than one note, and an error per link with an unknown `(kind)`. Malformed
+/// links, and a `#+kind:` line that disagrees with the file's extension, get
+/// warnings too.
+fn note_diagnostics(index: &Index, note: &Note) -> Vec<Diagnostic> {
+    let broken = index.broken_links(note).into_iter().map(|(id, range)| Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::WARNING),
+        message: match id.kind {
+            NoteKind::Any => format!("No note named '{}' found", id.name),
+            _ => format!("No {} named '{}' found", id.kind.display_name(), id.name),
+        },
+        code: Some(NumberOrString::String(NOTE_NOT_FOUND.to_string())),
+        source: Some("noteboks".to_string()),
+        ..Default::default()
+    });
+
+    let ambiguous = index.ambiguous_links(note).into_iter().map(|(id, range, candidates)| {
+        let files: Vec<String> =
+            candidates.iter().map(|c| c.to_filename().display().to_string()).collect();
+        // Point at each note the link could mean, so they're a click away.
+        let related = candidates
+            .iter()
+            .filter_map(|candidate| {
+                let path = index.path_of(index.notes.get(candidate)?)?;
+                Some(DiagnosticRelatedInformation {
+                    location: Location::new(Url::from_file_path(path).ok()?, Range::default()),
+                    message: format!("Could be this {}", candidate.kind.display_name()),
                 })
-                .await;
-        });
+            })
+            .collect();
+        Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::WARNING),
+            message: format!(
+                "'{}' could mean any of {}; add a kind to disambiguate, e.g. [[{}]]",
+                id.name,
+                files.join(", "),
+                crate::index::link_text(&id.name.replace('-', " "), &candidates[0].kind),
+            ),
+            related_information: Some(related),
+            source: Some("noteboks".to_string()),
+            ..Default::default()
+        }
+    });
+
+    let missing_headings = index.broken_anchors(note).into_iter().map(|(id, anchor, range)| Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::WARNING),
+        message: format!("Note '{}' exists but has no heading '{}'", id.name, anchor),
+        source: Some("noteboks".to_string()),
+        ..Default::default()
+    });
+
+    let unknown_kinds = note.unknown_kinds().into_iter().map(|(kind, range)| {
+        let kinds: Vec<String> = NoteKind::all().iter().map(|k| k.to_str().to_string()).collect();
+        Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::ERROR),
+            message: format!("Unknown kind '{}'; expected one of {}", kind, kinds.join(", ")),
+            source: Some("noteboks".to_string()),
+            ..Default::default()
+        }
+    });
+
+    let malformed = note.malformed_links().into_iter().map(|(err, range)| Diagnostic {
+        range,
+        // An unclosed link is usually one still being typed.
+        severity: Some(match err {
+            LinkError::Unterminated => DiagnosticSeverity::HINT,
+            _ => DiagnosticSeverity::WARNING,
+        }),
+        message: match err {
+            LinkError::Empty => "Empty link target".to_string(),
+            LinkError::Unterminated => "Unterminated link".to_string(),
+            err => format!("Malformed link: {}", err),
+        },
+        source: Some("noteboks".to_string()),
+        ..Default::default()
+    });
+
+    let declared_kind = crate::index::kind_keyword(&note.content()).and_then(|(kind, range)| {
+        let message = match NoteKind::from_str(&kind) {
+            Some(declared) if declared == note.id.kind => return None,
+            Some(declared) => format!(
+                "Declared as '{}' but the file is a .{}; rename it to {} or change the keyword",
+                declared.display_name(),
+                note.id.kind.to_str(),
+                NoteID { name: note.id.name.clone(), kind: declared }.to_filename().display(),
+            ),
+            None => format!("'{}' isn't a kind of note", kind),
+        };
+        Some(Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::WARNING),
+            message,
+            source: Some("noteboks".to_string()),
+            ..Default::default()
+        })
+    });
+
+    broken
+        .chain(ambiguous)
+        .chain(missing_headings)
+        .chain(unknown_kinds)
+        .chain(malformed)
+        .chain(declared_kind)
+        .collect()
+}
+
+/// The code actions that turn the selected text, or the word under the
+/// cursor, into a link: one for each note it names, best first. A selection
+/// that names no note can instead create one and link to it; that isn't
+/// offered for the word under the cursor, which would be on every word. The
+/// selection is widened to whole words, and never reaches into a link.
+fn link_selection_actions(
+    index: &Index,
+    note: &Note,
+    uri: &Url,
+    range: &Range,
+) -> Vec<CodeActionOrCommand> {
+    let content = note.content();
+    let row = range.start.line as usize;
+    let line = match content.lines().nth(row) {
+        Some(line) if range.end.line == range.start.line => line,
+        _ => return vec![],
+    };
+
+    let is_word = |c: char| c.is_alphanumeric() || c == '-' || c == '_' || c == '\'';
+    let start = crate::offsets::byte_col(line, range.start.character);
+    let end = crate::offsets::byte_col(line, range.end.character);
+    let start = line[..start]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_word(*c))
+        .last()
+        .map_or(start, |(i, _)| i);
+    let end = line[end..].find(|c| !is_word(c)).map_or(line.len(), |i| end + i);
+    let selected = &line[start..end];
+    let (start, end) =
+        (start + selected.len() - selected.trim_start().len(), start + selected.trim_end().len());
+    if start >= end || LINK_RE.find_iter(line).any(|m| m.start() < end && start < m.end()) {
+        return vec![];
+    }
+    let text = &line[start..end];
+    let id = match NoteID::parse_link(text) {
+        Ok(id) => id,
+        Err(_) => return vec![],
+    };
+
+    let link_edit = |link: String| TextEdit {
+        range: crate::offsets::range(line, row, start, end),
+        new_text: format!("[[{}]]", link),
+    };
+    let candidates = index.resolve_candidates(&id);
+    let action = |title: String, edit: WorkspaceEdit| {
+        CodeActionOrCommand::CodeAction(CodeAction {
+            title,
+            kind: Some(CodeActionKind::REFACTOR_REWRITE),
+            edit: Some(edit),
+            ..Default::default()
+        })
+    };
+
+    if candidates.is_empty() {
+        if range.start == range.end {
+            return vec![];
+        }
+        let new_id = match id.kind {
+            NoteKind::Any => NoteID { name: id.name.clone(), kind: NoteKind::Note },
+            _ => id.clone(),
+        };
+        // The new note goes where `noteboks.createNote` would put it.
+        let root = note.path.as_deref().and_then(|p| index.root_of(p)).or_else(|| index.default_root());
+        let new_path = root.map(|root| root.join(new_id.to_filename()));
+        let new_uri = match new_path.and_then(|path| Url::from_file_path(path).ok()) {
+            Some(uri) => uri,
+            None => return vec![],
+        };
+        let operations = vec![
+            DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                uri: new_uri.clone(),
+                // A file that's there but not indexed mustn't get a second
+                // title, so the edit fails instead.
+                options: Some(CreateFileOptions {
+                    overwrite: Some(false),
+                    ignore_if_exists: Some(false),
+                }),
+                annotation_id: None,
+            })),
+            DocumentChangeOperation::Edit(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier {
+                    uri: new_uri,
+                    version: None,
+                },
+                edits: vec![OneOf::Left(TextEdit {
+                    range: Range::default(),
+                    new_text: format!("# {}\n", text),
+                })],
+            }),
+            DocumentChangeOperation::Edit(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier {
+                    uri: uri.clone(),
+                    version: None,
+                },
+                edits: vec![OneOf::Left(link_edit(text.to_string()))],
+            }),
+        ];
+        let edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(operations)),
+            ..Default::default()
+        };
+        return vec![action(format!("Create note '{}' and link", text), edit)];
+    }
+
+    // A name several notes share gets a kind, so the link isn't ambiguous.
+    let ambiguous = candidates.len() > 1;
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let title = candidate.display_title();
+            let (title, link) = if ambiguous {
+                (
+                    format!("Link to '{}' ({})", title, candidate.id.kind.to_str()),
+                    crate::index::link_text(text, &candidate.id.kind),
+                )
+            } else {
+                (format!("Link to '{}'", title), text.to_string())
+            };
+            let edit = WorkspaceEdit {
+                changes: Some([(uri.clone(), vec![link_edit(link)])].into_iter().collect()),
+                ..Default::default()
+            };
+            action(title, edit)
+        })
+        .collect()
+}
+
+/// What moving the section under the heading on `row` into a note of its own
+/// gives: the new note's ID, named after the heading and of the same kind;
+/// its content, in which the heading is the title and those under it are
+/// raised to match; and the range of the section here, which the link to
+/// the new note replaces. Blank lines after the section stay where they are.
+fn extract_subtree(note: &Note, row: u32) -> Option<(NoteID, String, Range)> {
+    let headings = note.headings();
+    let heading = headings.iter().find(|h| h.range.start.line == row)?;
+    let name: String = heading
+        .text
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { ' ' })
+        .collect();
+    let name = crate::index::normalize_name(&name);
+    if name.is_empty() {
+        return None;
     }
+    let id = NoteID { name, kind: note.id.kind.clone() };
+
+    let content = note.content();
+    let lines: Vec<&str> = content.lines().collect();
+    let first = row as usize;
+    let last = (first..=heading.section.end.line as usize)
+        .rev()
+        .find(|&r| lines.get(r).is_some_and(|line| !line.trim().is_empty()))?;
+    let raise = heading.level - 1;
+    let is_heading = |r: usize| headings.iter().any(|h| h.range.start.line as usize == r);
+
+    let mut extracted = String::new();
+    for (r, line) in lines.iter().enumerate().take(last + 1).skip(first) {
+        extracted.push_str(if is_heading(r) { &line[raise..] } else { line });
+        extracted.push('\n');
+    }
+    let range = Range::new(
+        Position::new(row, 0),
+        Position::new(last as u32, crate::offsets::utf16_col(lines[last], lines[last].len())),
+    );
+    Some((id, extracted, range))
+}
+
+/// The ways to change the TODO keyword of the heading on `row`, as titled
+/// edits: set it to the first open keyword, set it to the first done
+/// keyword, or remove it. The keyword always comes straight after the `#`s,
+/// before any priority cookie or title, so nothing else on the line moves.
+fn todo_keyword_edits(content: &str, row: usize) -> Vec<(String, TextEdit)> {
+    let line = match crate::index::body_lines(content).into_iter().find(|(r, _)| *r == row) {
+        Some((_, line)) => line,
+        None => return vec![],
+    };
+    let hashes = line.len() - line.trim_start_matches('#').len();
+    let rest = &line[hashes..];
+    if hashes == 0 || hashes > 6 || !rest.starts_with(char::is_whitespace) {
+        return vec![];
+    }
+    let start = hashes + (rest.len() - rest.trim_start().len());
+
+    let (open, done) = crate::index::todo_keywords(content);
+    let word = line[start..].split_whitespace().next().unwrap_or("");
+    let current = open.iter().chain(&done).find(|k| *k == word);
+    // The keyword and the space after it, or nothing if there isn't one.
+    let end = match current {
+        Some(keyword) => {
+            let after = start + keyword.len();
+            after + (line[after..].len() - line[after..].trim_start().len())
+        }
+        None => start,
+    };
+    let edit = |new_text: String| TextEdit { range: crate::offsets::range(line, row, start, end), new_text };
+
+    let mut edits = Vec::new();
+    for keyword in [open.first(), done.first()].into_iter().flatten() {
+        if current != Some(keyword) {
+            edits.push((format!("Set {}", keyword), edit(format!("{} ", keyword))));
+        }
+    }
+    if current.is_some() {
+        edits.push(("Remove keyword".to_string(), edit(String::new())));
+    }
+    edits
 }
 
 /// Find the `#tag` name at a given (line, col) position in content.
 /// Returns the tag name without the leading `#`.
 fn find_tag_at(content: &str, line: u32, col: u32) -> Option<String> {
     let line_text = content.lines().nth(line as usize)?;
-    let re = Regex::new(r"#([a-zA-Z][a-zA-Z0-9_\-]*)").unwrap();
-    for cap in re.captures_iter(line_text) {
+    let col = crate::offsets::byte_col(line_text, col);
+    for cap in TAG_RE.captures_iter(line_text) {
         let full = cap.get(0).unwrap();
-        if col as usize >= full.start() && (col as usize) < full.end() {
+        if col >= full.start() && col < full.end() {
             return Some(cap.get(1).unwrap().as_str().to_string());
         }
     }
     None
 }
 
-/// Find the `[[link target]]` text at a given (line, col) position in content.
-fn find_link_at(content: &str, line: u32, col: u32) -> Option<String> {
+/// A `[[link]]` found at some position in a note.
+struct LinkAt {
+    /// The text between the brackets.
+    text: String,
+    /// The whole link, brackets included.
+    range: Range,
+    /// Just the note name, without the `(kind)` or anchor; `None` when the
+    /// link doesn't name a note.
+    name_range: Option<Range>,
+}
+
+/// Find the `[[link]]` at a given (line, col) position in content, where
+/// `col` counts UTF-16 code units as LSP positions do.
+fn link_at(content: &str, line: u32, col: u32) -> Option<LinkAt> {
     let line_text = content.lines().nth(line as usize)?;
-    let re = Regex::new(r"\[\[([^\]]+)\]\]").unwrap();
-    for cap in re.captures_iter(line_text) {
+    let col = crate::offsets::byte_col(line_text, col);
+    for cap in LINK_RE.captures_iter(line_text) {
         let full = cap.get(0).unwrap();
-        if col as usize >= full.start() && (col as usize) < full.end() {
-            return Some(cap.get(1).unwrap().as_str().to_string());
+        if col >= full.start() && col < full.end() {
+            let inner = cap.get(1).unwrap();
+            let row = line as usize;
+            return Some(LinkAt {
+                text: inner.as_str().to_string(),
+                range: crate::offsets::range(line_text, row, full.start(), full.end()),
+                name_range: crate::index::link_name_span(inner.as_str()).map(|span| {
+                    crate::offsets::range(line_text, row, inner.start() + span.start, inner.start() + span.end)
+                }),
+            });
         }
     }
     None
 }
 
-/// Extract the partial link text being typed after `[[` on a line, up to `col`.
-/// Returns `(partial_text, partial_start_col)` where `partial_start_col` is the
-/// column index of the first character after `[[`.  Returns `None` if the cursor
-/// is not inside an open `[[`.
+/// Find the `[[link target]]` text at a given (line, col) position in content.
+fn find_link_at(content: &str, line: u32, col: u32) -> Option<String> {
+    link_at(content, line, col).map(|link| link.text)
+}
+
+/// Find what a `[[link]]` or `#tag` at a given (line, col) position points at.
+/// Any column inside the link, brackets included, counts as being on it.
+fn target_at(content: &str, line: u32, col: u32) -> Option<LinkTarget> {
+    if let Some(link_target) = find_link_at(content, line, col) {
+        LinkTarget::from_link(&link_target)
+    } else {
+        find_tag_at(content, line, col).map(|tag| LinkTarget {
+            id: NoteID { name: crate::index::normalize_name(&tag), kind: NoteKind::Any },
+            anchor: None,
+        })
+    }
+}
+
+/// How well `query` fuzzily matches `text`, higher being better, or `None`
+/// if it doesn't match at all. Every character of the query has to appear in
+/// order, so "rcp" matches "recipes". Each character of `text` left over
+/// costs a point, so that of two similar matches the closer one wins. An
+/// empty query matches everything.
+fn fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    static MATCHER: LazyLock<SkimMatcherV2> = LazyLock::new(|| SkimMatcherV2::default().ignore_case());
+    if query.is_empty() {
+        return Some(0);
+    }
+    let unmatched = text.chars().count().saturating_sub(query.chars().count());
+    MATCHER.fuzzy_match(text, query).map(|score| score - unmatched as i64)
+}
+
+/// Sort key for a completion: preferred items first, then by name.
+fn completion_sort_text(is_prefix: bool, name: &str) -> String {
+    format!("{}{}", if is_prefix { 0 } else { 1 }, name)
+}
+
+/// Nest headings into a `DocumentSymbol` tree, so that each heading becomes a
+/// child of the closest preceding shallower heading. Task headings get their
+/// keyword (`TODO`, `DONE`, ...) as detail.
+#[allow(deprecated)]
+fn heading_symbols(headings: Vec<Heading>, tasks: &[Task]) -> Vec<DocumentSymbol> {
+    let mut roots: Vec<DocumentSymbol> = Vec::new();
+    let mut stack: Vec<(usize, DocumentSymbol)> = Vec::new();
+
+    // Pop the top of the stack into its parent (or the roots).
+    fn close(stack: &mut Vec<(usize, DocumentSymbol)>, roots: &mut Vec<DocumentSymbol>) {
+        if let Some((_, symbol)) = stack.pop() {
+            match stack.last_mut() {
+                Some((_, parent)) => parent.children.get_or_insert_with(Vec::new).push(symbol),
+                None => roots.push(symbol),
+            }
+        }
+    }
+
+    for heading in headings {
+        while stack.last().is_some_and(|(l, _)| *l >= heading.level) {
+            close(&mut stack, &mut roots);
+        }
+        stack.push((
+            heading.level,
+            DocumentSymbol {
+                detail: tasks
+                    .iter()
+                    .find(|t| t.range.start.line == heading.range.start.line)
+                    .map(|t| t.state.clone()),
+                name: heading.text,
+                kind: SymbolKind::STRING,
+                tags: None,
+                deprecated: None,
+                range: heading.section,
+                selection_range: heading.range,
+                children: None,
+            },
+        ));
+    }
+    while !stack.is_empty() {
+        close(&mut stack, &mut roots);
+    }
+
+    roots
+}
+
+/// Every link or tag elsewhere in the vault that points at a note.
+fn backlink_locations(index: &Index, id: &NoteID) -> Vec<Location> {
+    let mut locations = Vec::new();
+    for (source, ranges) in index.backlinks_to(id) {
+        // Skip the note itself
+        if source == *id {
+            continue;
+        }
+        let uri = match index
+            .notes
+            .get(&source)
+            .and_then(|n| index.path_of(n))
+            .and_then(|p| Url::from_file_path(p).ok())
+        {
+            Some(uri) => uri,
+            None => continue,
+        };
+        for range in ranges {
+            locations.push(Location { uri: uri.clone(), range });
+        }
+    }
+    locations
+}
+
+/// The note a position is "about": the target of the link or tag under it, or
+/// failing that the note the position is in.
+fn subject_at(index: &Index, uri: &Url, position: Position) -> Option<NoteID> {
+    let linked = index
+        .note_at_uri(uri)
+        .and_then(|n| n.document.as_ref())
+        .and_then(|doc| target_at(doc.get_content(None), position.line, position.character))
+        .and_then(|target| index.resolve_link(&target.id))
+        .map(|n| n.id.clone());
+    linked.or_else(|| NoteID::from_uri(uri))
+}
+
+/// The text covered by a single-line range.
+fn text_in_range<'a>(content: &'a str, range: &Range) -> Option<&'a str> {
+    content
+        .lines()
+        .nth(range.start.line as usize)
+        .and_then(|line| line.get(crate::offsets::byte_range(line, range)))
+}
+
+/// Rewrite a `[[link]]` or `#tag` so that it points at `new_name` instead,
+/// keeping any explicit `(kind)` suffix and `#heading` anchor. A path link
+/// keeps its directory and gets the new file name.
+fn rewrite_link(text: &str, new_name: &str) -> Option<String> {
+    if let Some(inner) = text.strip_prefix("[[").and_then(|t| t.strip_suffix("]]")) {
+        let target = LinkTarget::from_link(inner)?;
+        let (note, anchor) = crate::index::split_anchor(inner);
+        if crate::index::is_path_link(note) {
+            let span = crate::index::link_name_span(note)?;
+            let name = crate::index::normalize_name(new_name);
+            return Some(format!("[[{}{}{}]]", &note[..span.start], name, &inner[span.end..]));
+        }
+        Some(format!("[[{}{}]]", crate::index::link_text(new_name, &target.id.kind), anchor))
+    } else if text.starts_with('#') {
+        Some(format!("#{}", crate::index::normalize_name(new_name)))
+    } else {
+        None
+    }
+}
+
+/// Rewrite a `[[link]]` so that its `(kind)` suffix, or a path's extension,
+/// says `kind`. Links without either, and tags, are left as they are.
+fn rewrite_link_kind(text: &str, kind: &NoteKind) -> Option<String> {
+    let inner = text.strip_prefix("[[").and_then(|t| t.strip_suffix("]]"))?;
+    let target = LinkTarget::from_link(inner)?;
+    let (note, anchor) = crate::index::split_anchor(inner);
+    let span = crate::index::link_name_span(note)?;
+    if crate::index::is_path_link(note) {
+        return Some(format!("[[{}.{}{}]]", &note[..span.end], kind.to_str(), anchor));
+    }
+    if target.id.kind == NoteKind::Any {
+        return None;
+    }
+    Some(format!("[[{}{}]]", crate::index::link_text(&note[span], kind), anchor))
+}
+
+/// The edits to each note linking to `old_id` that rewrite every link naming
+/// it directly with `rewrite`. Links through an alias keep working as they
+/// are.
+fn backlink_edits(
+    index: &Index,
+    old_id: &NoteID,
+    rewrite: impl Fn(&str) -> Option<String>,
+) -> Vec<(Url, Vec<TextEdit>)> {
+    let mut changes = Vec::new();
+    for (source, ranges) in index.backlinks_to(old_id) {
+        let note = match index.notes.get(&source) {
+            Some(note) => note,
+            None => continue,
+        };
+        let uri = match index.path_of(note).and_then(|path| Url::from_file_path(path).ok()) {
+            Some(uri) => uri,
+            None => continue,
+        };
+        let content = note.content();
+        let edits: Vec<TextEdit> = ranges
+            .iter()
+            .filter_map(|range| {
+                let text = text_in_range(&content, range)?;
+                let target = target_at(text, 0, 0)?.id;
+                if target.name != old_id.name {
+                    return None;
+                }
+                let new_text = rewrite(text).filter(|new_text| new_text != text)?;
+                Some(TextEdit { range: *range, new_text })
+            })
+            .collect();
+        if !edits.is_empty() {
+            changes.push((uri, edits));
+        }
+    }
+    changes
+}
+
+/// The edit that moves the note `old_id` from `old_path` to `new_path`,
+/// rewriting the links to it with `rewrite`, as `backlink_edits` does. None
+/// if either path can't be written as a URI.
+fn move_note_edit(
+    index: &Index,
+    old_id: &NoteID,
+    old_path: &Path,
+    new_path: &Path,
+    rewrite: impl Fn(&str) -> Option<String>,
+) -> Option<WorkspaceEdit> {
+    let old_uri = Url::from_file_path(old_path).ok()?;
+    let new_uri = Url::from_file_path(new_path).ok()?;
+    let mut operations: Vec<DocumentChangeOperation> = backlink_edits(index, old_id, rewrite)
+        .into_iter()
+        .map(|(uri, edits)| {
+            DocumentChangeOperation::Edit(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+                edits: edits.into_iter().map(OneOf::Left).collect(),
+            })
+        })
+        .collect();
+
+    operations.push(DocumentChangeOperation::Op(ResourceOp::Rename(RenameFile {
+        old_uri,
+        new_uri,
+        options: Some(RenameFileOptions {
+            overwrite: Some(false),
+            ignore_if_exists: Some(false),
+        }),
+        annotation_id: None,
+    })));
+
+    Some(WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Operations(operations)),
+        ..Default::default()
+    })
+}
+
+/// Where the link or tag at a position leads: the title of each note it could
+/// mean, best first. With `to_anchor`, a link's `#heading` is followed too,
+/// when the note has that heading.
+fn target_locations(
+    index: &Index,
+    params: &TextDocumentPositionParams,
+    to_anchor: bool,
+) -> Vec<Location> {
+    let note = match index.note_at_uri(&params.text_document.uri) {
+        Some(note) => note,
+        None => return vec![],
+    };
+    let (line, col) = (params.position.line, params.position.character);
+    let target = match target_at(&note.content(), line, col) {
+        Some(target) => target,
+        None => return vec![],
+    };
+
+    index
+        .resolve_candidates(&target.id)
+        .into_iter()
+        .filter_map(|note| {
+            let uri = Url::from_file_path(index.path_of(note)?).ok()?;
+            let start = target
+                .anchor
+                .as_deref()
+                .filter(|_| to_anchor)
+                .and_then(|anchor| note.heading(anchor))
+                .map(|h| h.range.start)
+                .unwrap_or_else(|| note.title_position());
+            Some(Location { uri, range: Range::new(start, start) })
+        })
+        .collect()
+}
+
+/// A response for go-to-definition (or declaration) with these locations:
+/// a single location on its own, and several for an ambiguous link.
+fn goto_response(locations: Vec<Location>) -> Option<GotoDefinitionResponse> {
+    match locations.len() {
+        0 => None,
+        1 => locations.into_iter().next().map(GotoDefinitionResponse::Scalar),
+        _ => Some(GotoDefinitionResponse::Array(locations)),
+    }
+}
+
+/// Extract the partial link text being typed after `[[` on a line, up to byte
+/// `col`. Returns `(partial_text, partial_start_col)` where `partial_start_col`
+/// is the byte offset of the first character after `[[`.  Returns `None` if the
+/// cursor is not inside an open `[[`.
 fn partial_link_at(line_text: &str, col: usize) -> Option<(String, usize)> {
-    let prefix = &line_text[..col.min(line_text.len())];
+    // Never slice mid-character, or past the end of the line.
+    let end = (0..=col.min(line_text.len())).rev().find(|&i| line_text.is_char_boundary(i))?;
+    let prefix = &line_text[..end];
     // Find the last `[[` before the cursor that isn't closed by `]]`
     let open = prefix.rfind("[[")?;
     let after_open = &prefix[open + 2..];
@@ -214,31 +1119,172 @@ fn partial_link_at(line_text: &str, col: usize) -> Option<(String, usize)> {
     Some((after_open.to_string(), open + 2))
 }
 
+/// Completions for a tag being typed at `col`: after `#` in the text, or in
+/// an org-style `:tag:tag:` group at the end of a heading, where accepting
+/// one also adds the closing `:`. Tags already on the line aren't offered.
+fn tag_completions(index: &Index, line_text: &str, line: u32, col: u32) -> Option<CompletionResponse> {
+    static HASH_TAG_RE: LazyLock<Regex> =
+        LazyLock::new(|| crate::index::pattern("partial tag", r"(?:^|\s)#([\w-]*)$"));
+    static HEADLINE_TAGS_RE: LazyLock<Regex> =
+        LazyLock::new(|| crate::index::pattern("partial headline tag", r"\s:(?:[\w-]+:)*([\w-]*)$"));
+
+    let cursor = crate::offsets::byte_col(line_text, col);
+    let prefix = &line_text[..cursor];
+    let is_heading = line_text.starts_with('#') && line_text.trim_start_matches('#').starts_with(' ');
+
+    // The tag typed so far, the text to close it with, and the rest of the
+    // line's `:tag:` group.
+    let (partial, closing, group) = match HASH_TAG_RE.captures(prefix) {
+        // `#` alone at the start of a line is a heading, not a tag.
+        Some(cap) if !(is_heading && cap.get(0).unwrap().start() == 0) => (cap.get(1).unwrap(), "", String::new()),
+        _ => match HEADLINE_TAGS_RE.captures(prefix).filter(|_| is_heading) {
+            Some(cap) => {
+                let partial = cap.get(1).unwrap();
+                let before = &prefix[cap.get(0).unwrap().start()..partial.start()];
+                (partial, ":", format!("{}{}", before, &line_text[cursor..]))
+            }
+            None => return None,
+        },
+    };
+
+    // Leave out what's being typed, so a finished tag is still offered.
+    let elsewhere = format!("{} {}", &line_text[..partial.start()], &line_text[cursor..]);
+    let present: HashSet<String> = crate::index::scan_tags(&elsewhere)
+        .into_iter()
+        .map(|(tag, _)| tag)
+        .chain(group.split(':').map(|tag| tag.trim().to_string()))
+        .map(|tag| crate::index::normalize_name(&tag))
+        .collect();
+    let typed = crate::index::normalize_name(partial.as_str());
+    let range = Range::new(
+        Position::new(line, crate::offsets::utf16_col(line_text, partial.start())),
+        Position::new(line, col),
+    );
+    let closed = line_text[cursor..].starts_with(':');
+
+    let items: Vec<CompletionItem> = index
+        .all_tags()
+        .filter(|tag| !present.contains(*tag) && tag.starts_with(&typed))
+        .map(|tag| CompletionItem {
+            label: tag.to_string(),
+            kind: Some(CompletionItemKind::CONSTANT),
+            detail: Some(format!("{} notes", index.notes_with_tag(tag).len())),
+            text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                range,
+                new_text: format!("{}{}", tag, if closed { "" } else { closing }),
+            })),
+            ..Default::default()
+        })
+        .collect();
+    if items.is_empty() { None } else { Some(CompletionResponse::Array(items)) }
+}
+
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
     async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
-        let root = params
-            .root_uri
+        let progress = params.capabilities.window.as_ref().and_then(|w| w.work_done_progress);
+        self.progress_supported.store(progress == Some(true), Ordering::SeqCst);
+        let watchers = params
+            .capabilities
+            .workspace
             .as_ref()
-            .and_then(|u| u.to_file_path().ok())
-            .or_else(|| {
-                params.workspace_folders.as_ref().and_then(|folders| {
-                    folders.first().and_then(|f| f.uri.to_file_path().ok())
-                })
-            });
+            .and_then(|w| w.did_change_watched_files.as_ref())
+            .and_then(|w| w.dynamic_registration);
+        self.watchers_supported.store(watchers == Some(true), Ordering::SeqCst);
+
+        let settings = Settings::from_options(params.initialization_options.as_ref());
+        NoteKind::set_custom(settings.kinds.clone());
+        *self.settings.lock().await = settings;
+
+        // Every workspace folder is a vault; failing that, the root URI or
+        // the NOTEBOKS_VAULT environment variable.
+        let mut roots: Vec<PathBuf> = params
+            .workspace_folders
+            .iter()
+            .flatten()
+            .filter_map(|f| f.uri.to_file_path().ok())
+            .collect();
+        if roots.is_empty() {
+            roots.extend(
+                params
+                    .root_uri
+                    .as_ref()
+                    .and_then(|u| u.to_file_path().ok())
+                    .or_else(|| std::env::var("NOTEBOKS_VAULT").ok().map(PathBuf::from)),
+            );
+        }
 
-        if let Some(root_path) = root {
-            let mut index = self.index.lock().await;
-            *index = Index::new(&root_path);
+        if roots.is_empty() {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    "No workspace root or NOTEBOKS_VAULT set; only open notes will be indexed",
+                )
+                .await
         }
+        let mut index = self.index.write().await;
+        for root in roots {
+            index.add_root(root);
+        }
+        drop(index);
 
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
                 hover_provider: Some(HoverProviderCapability::Simple(true)),
                 definition_provider: Some(OneOf::Left(true)),
+                declaration_provider: Some(DeclarationCapability::Simple(true)),
                 references_provider: Some(OneOf::Left(true)),
+                document_highlight_provider: Some(OneOf::Left(true)),
+                selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                document_range_formatting_provider: Some(OneOf::Left(true)),
+                document_on_type_formatting_provider: Some(DocumentOnTypeFormattingOptions {
+                    first_trigger_character: "\n".to_string(),
+                    more_trigger_character: None,
+                }),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+                code_lens_provider: Some(CodeLensOptions { resolve_provider: Some(false) }),
+                semantic_tokens_provider: Some(
+                    SemanticTokensServerCapabilities::SemanticTokensOptions(SemanticTokensOptions {
+                        legend: semantic_tokens::legend(),
+                        full: Some(SemanticTokensFullOptions::Bool(true)),
+                        range: None,
+                        work_done_progress_options: Default::default(),
+                    }),
+                ),
+                workspace_symbol_provider: Some(OneOf::Left(true)),
+                document_link_provider: Some(DocumentLinkOptions {
+                    resolve_provider: Some(false),
+                    work_done_progress_options: Default::default(),
+                }),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: Default::default(),
+                })),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        CREATE_NOTE_COMMAND.to_string(),
+                        INSERT_LINK_COMMAND.to_string(),
+                        OPEN_DAILY_NOTE_COMMAND.to_string(),
+                        LIST_ORPHANS_COMMAND.to_string(),
+                        FIND_ORPHANS_COMMAND.to_string(),
+                        LIST_CYCLES_COMMAND.to_string(),
+                        EXPORT_GRAPH_COMMAND.to_string(),
+                        RESCAN_COMMAND.to_string(),
+                        LIST_TASKS_COMMAND.to_string(),
+                        LIST_BACKLINKS_COMMAND.to_string(),
+                        CHANGE_KIND_COMMAND.to_string(),
+                        EXTRACT_SUBTREE_COMMAND.to_string(),
+                        PATH_BETWEEN_COMMAND.to_string(),
+                    ],
+                    work_done_progress_options: Default::default(),
+                }),
                 completion_provider: Some(CompletionOptions {
-                    trigger_characters: Some(vec!["[".to_string()]),
+                    trigger_characters: Some(
+                        ["[", "#", ":", "("].into_iter().map(String::from).collect(),
+                    ),
                     resolve_provider: Some(false),
                     ..Default::default()
                 }),
@@ -246,10 +1292,24 @@ impl LanguageServer for Backend {
                     TextDocumentSyncOptions {
                         open_close: Some(true),
                         change: Some(TextDocumentSyncKind::INCREMENTAL),
-                        save: Some(TextDocumentSyncSaveOptions::Supported(true)),
+                        save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                            include_text: Some(true),
+                        })),
                         ..Default::default()
                     },
                 )),
+                workspace: Some(WorkspaceServerCapabilities {
+                    workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                        supported: Some(true),
+                        change_notifications: Some(OneOf::Left(true)),
+                    }),
+                    file_operations: Some(WorkspaceFileOperationsServerCapabilities {
+                        did_create: Some(note_file_operations()),
+                        will_rename: Some(note_file_operations()),
+                        did_rename: Some(note_file_operations()),
+                        ..Default::default()
+                    }),
+                }),
                 ..Default::default()
             },
             ..Default::default()
@@ -257,160 +1317,321 @@ impl LanguageServer for Backend {
     }
 
     async fn initialized(&self, _: InitializedParams) {
+        self.register_watchers().await;
         self.trigger_scan();
     }
 
+    async fn did_change_workspace_folders(&self, params: DidChangeWorkspaceFoldersParams) {
+        let paths = |folders: Vec<WorkspaceFolder>| -> Vec<PathBuf> {
+            folders.into_iter().filter_map(|f| f.uri.to_file_path().ok()).collect()
+        };
+        let (added, removed) = (paths(params.event.added), paths(params.event.removed));
+
+        let mut dropped = Vec::new();
+        let added: Vec<PathBuf> = {
+            let mut index = self.index.write().await;
+            for root in &removed {
+                dropped.extend(index.remove_root(root));
+            }
+            added.into_iter().filter(|root| index.add_root(root.clone())).collect()
+        };
+
+        // Notes from removed vaults lose their diagnostics, and links to them
+        // from other vaults may now be broken.
+        let mut names = Vec::new();
+        for note in dropped {
+            names.push(note.id.name.clone());
+            names.extend(note.aliases.iter().map(|a| crate::index::normalize_name(a)));
+            if let Some(uri) = note.path.and_then(|p| Url::from_file_path(p).ok()) {
+                self.client.publish_diagnostics(uri, vec![], None).await;
+            }
+        }
+        if self.watchers_supported.load(Ordering::SeqCst) {
+            let _ = self
+                .client
+                .unregister_capability(vec![Unregistration {
+                    id: WATCHED_FILES_REGISTRATION.to_string(),
+                    method: "workspace/didChangeWatchedFiles".to_string(),
+                }])
+                .await;
+            self.register_watchers().await;
+        }
+        if !added.is_empty() {
+            self.scan(added, false).await;
+        }
+        self.publish_linking_diagnostics(vec![], &names).await;
+    }
+
     async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
         let pos = params.text_document_position_params;
         let uri = pos.text_document.uri;
         let line = pos.position.line;
         let col = pos.position.character;
 
-        let index = self.index.lock().await;
+        let (preview_lines, max_backlinks) = {
+            let settings = self.settings.lock().await;
+            (settings.preview_lines, settings.hover_backlinks)
+        };
+        let index = self.index.read().await;
 
         let content = match index.note_at_uri(&uri).and_then(|n| n.document.as_ref()) {
             Some(doc) => doc.get_content(None).to_string(),
             None => return Ok(None),
         };
+        // Clients may hover past the end of the note, e.g. in virtual space.
+        if line as usize >= content.lines().count() {
+            return Ok(None);
+        }
 
-        let id = if let Some(link_target) = find_link_at(&content, line, col) {
-            NoteID::from_link(&link_target)
-        } else if let Some(tag) = find_tag_at(&content, line, col) {
-            Some(NoteID { name: crate::index::normalize_name(&tag), kind: NoteKind::Any })
-        } else {
-            None
-        };
-
-        if let Some(id) = id {
-            let hover_text = if let Some(linked_note) = index.resolve_link(&id) {
-                let title = linked_note.title.as_deref().unwrap_or(linked_note.id.name.as_str());
-                format!("→ **{}** ({})", title, linked_note.id.kind.to_str())
+        if let Some(LinkTarget { id, anchor }) = target_at(&content, line, col) {
+            let candidates = index.resolve_candidates(&id);
+            let hover_text = if let Some(linked_note) = candidates.first() {
+                // Preview the anchored heading's section if there is one.
+                let heading = anchor.as_deref().and_then(|a| linked_note.heading(a));
+                let mut text = match &heading {
+                    Some(h) => format!(
+                        "**{} › {}** ({})",
+                        linked_note.display_title(),
+                        h.text,
+                        linked_note.id.kind.display_name()
+                    ),
+                    None => format!(
+                        "**{}** ({})",
+                        linked_note.display_title(),
+                        linked_note.id.kind.display_name()
+                    ),
+                };
+                let excerpt = match &heading {
+                    Some(h) => linked_note.section_excerpt(h, preview_lines),
+                    None => linked_note.excerpt(preview_lines),
+                };
+                if !excerpt.is_empty() {
+                    text.push_str("\n\n");
+                    text.push_str(&excerpt.join("\n"));
+                }
+                if candidates.len() > 1 {
+                    let others: Vec<String> =
+                        candidates[1..].iter().map(|n| n.id.kind.display_name()).collect();
+                    text.push_str(&format!("\n\n*Also exists as: {}*", others.join(", ")));
+                }
+                text
             } else {
-                format!("→ {} (new note)", id.name)
+                match id.kind {
+                    NoteKind::Any => format!("Note not found: {}", id.name),
+                    _ => format!("Note not found: {} ({})", id.name, id.kind.display_name()),
+                }
             };
             return Ok(Some(Hover {
                 contents: HoverContents::Markup(MarkupContent {
                     kind: MarkupKind::Markdown,
                     value: hover_text,
                 }),
-                range: None,
+                // Tags have no `LinkAt`, so they get no highlighted range.
+                range: link_at(&content, line, col).map(|link| link.range),
             }));
         }
 
-        Ok(None)
+        // Not on a link: on the title heading, list what links here instead.
+        let note = match index.note_at_uri(&uri) {
+            Some(note) => note,
+            None => return Ok(None),
+        };
+        let on_title = note
+            .headings()
+            .into_iter()
+            .find(|h| h.level == 1)
+            .is_some_and(|h| h.range.start.line == line);
+        if !on_title {
+            return Ok(None);
+        }
+
+        let backlinks: Vec<NoteID> = index
+            .backlinks_to(&note.id)
+            .into_keys()
+            .filter(|id| *id != note.id)
+            .collect();
+        let value = if backlinks.is_empty() {
+            "No backlinks".to_string()
+        } else {
+            let mut lines: Vec<String> = backlinks
+                .iter()
+                .take(max_backlinks)
+                .map(|id| match index.notes.get(id) {
+                    Some(n) => format!("- {} ({})", n.display_title(), id.kind.display_name()),
+                    None => format!("- {} ({})", id.name, id.kind.display_name()),
+                })
+                .collect();
+            if backlinks.len() > max_backlinks {
+                lines.push(format!("- …and {} more", backlinks.len() - max_backlinks));
+            }
+            let count = match backlinks.len() {
+                1 => "1 note".to_string(),
+                n => format!("{} notes", n),
+            };
+            format!("**Linked from {}**\n\n{}", count, lines.join("\n"))
+        };
+
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent { kind: MarkupKind::Markdown, value }),
+            range: None,
+        }))
     }
 
     async fn goto_definition(
         &self,
         params: GotoDefinitionParams,
     ) -> Result<Option<GotoDefinitionResponse>> {
-        let pos = params.text_document_position_params;
-        let uri = pos.text_document.uri;
-        let line = pos.position.line;
-        let col = pos.position.character;
+        let index = self.index.read().await;
+        let locations = target_locations(&index, &params.text_document_position_params, false);
+        Ok(goto_response(locations))
+    }
+
+    async fn goto_declaration(
+        &self,
+        params: GotoDeclarationParams,
+    ) -> Result<Option<GotoDeclarationResponse>> {
+        let index = self.index.read().await;
+        let locations = target_locations(&index, &params.text_document_position_params, true);
+        Ok(goto_response(locations))
+    }
 
-        let index = self.index.lock().await;
+    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
+        let pos = params.text_document_position;
+        let uri = pos.text_document.uri;
+        let index = self.index.read().await;
 
-        // Extract content before taking any further borrows
-        let content = match index.note_at_uri(&uri).and_then(|n| n.document.as_ref()) {
-            Some(doc) => doc.get_content(None).to_string(),
+        let target_id = match subject_at(&index, &uri, pos.position) {
+            Some(id) => id,
             None => return Ok(None),
         };
 
-        let id = if let Some(link_target) = find_link_at(&content, line, col) {
-            match NoteID::from_link(&link_target) {
-                Some(id) => id,
-                None => return Ok(None),
-            }
-        } else if let Some(tag) = find_tag_at(&content, line, col) {
-            NoteID { name: crate::index::normalize_name(&tag), kind: NoteKind::Any }
+        let locations = backlink_locations(&index, &target_id);
+        if locations.is_empty() {
+            Ok(None)
         } else {
-            return Ok(None);
+            Ok(Some(locations))
+        }
+    }
+
+    async fn selection_range(&self, params: SelectionRangeParams) -> Result<Option<Vec<SelectionRange>>> {
+        let index = self.index.read().await;
+        let note = match index.note_at_uri(&params.text_document.uri) {
+            Some(note) => note,
+            None => return Ok(None),
+        };
+        let content = match &note.document {
+            Some(doc) => doc.get_content(None),
+            None => return Ok(None),
         };
 
-        // Resolve existing note — clone path out to drop the borrow
-        if let Some(path) = index.resolve_link(&id).and_then(|n| n.path.clone()) {
-            return Ok(Some(GotoDefinitionResponse::Scalar(Location {
-                uri: Url::from_file_path(&path).unwrap(),
-                range: Range::new(Position::new(0, 0), Position::new(0, 0)),
-            })));
-        }
+        let headings = note.headings();
+        Ok(Some(
+            params
+                .positions
+                .into_iter()
+                .map(|position| selection::selection_range(content, &headings, position))
+                .collect(),
+        ))
+    }
 
-        // Note doesn't exist — point to where it would be and let the editor create it
-        let kind = if id.kind == NoteKind::Any { NoteKind::Note } else { id.kind.clone() };
-        let new_id = NoteID { name: id.name.clone(), kind };
-        let path = index.root.join(new_id.to_filename());
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let index = self.index.read().await;
+        let content = match index.note_at_uri(&params.text_document.uri).and_then(|n| n.document.as_ref()) {
+            Some(doc) => doc.get_content(None),
+            None => return Ok(None),
+        };
 
-        Ok(Some(GotoDefinitionResponse::Scalar(Location {
-            uri: Url::from_file_path(&path).unwrap(),
-            range: Range::new(Position::new(0, 0), Position::new(0, 0)),
-        })))
+        let edits = formatting::format_tables(content);
+        if edits.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(edits))
+        }
     }
 
-    async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
-        let uri = params.text_document_position.text_document.uri;
-        let index = self.index.lock().await;
+    async fn range_formatting(
+        &self,
+        params: DocumentRangeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let index = self.index.read().await;
+        let content = match index.note_at_uri(&params.text_document.uri).and_then(|n| n.document.as_ref()) {
+            Some(doc) => doc.get_content(None),
+            None => return Ok(None),
+        };
 
-        let target_id = match NoteID::from_uri(&uri) {
-            Some(id) => id,
+        let edits = formatting::format_tables_in(content, &params.range);
+        if edits.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(edits))
+        }
+    }
+
+    async fn on_type_formatting(
+        &self,
+        params: DocumentOnTypeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let pos = params.text_document_position;
+        if params.ch != "\n" {
+            return Ok(None);
+        }
+        let index = self.index.read().await;
+        let content = match index.note_at_uri(&pos.text_document.uri).and_then(|n| n.document.as_ref()) {
+            Some(doc) => doc.get_content(None),
             None => return Ok(None),
         };
 
-        // All normalised names this note can be reached by (its own name + all aliases)
-        let target_note = index.notes.get(&target_id);
-        let mut target_names: Vec<String> = vec![target_id.name.clone()];
-        if let Some(note) = target_note {
-            for alias in &note.aliases {
-                target_names.push(crate::index::normalize_name(alias));
-            }
+        let edits = formatting::continue_list(content, pos.position);
+        if edits.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(edits))
         }
+    }
 
-        let mut locations = Vec::new();
+    async fn document_highlight(
+        &self,
+        params: DocumentHighlightParams,
+    ) -> Result<Option<Vec<DocumentHighlight>>> {
+        let pos = params.text_document_position_params;
+        let index = self.index.read().await;
 
-        for (_, note) in &index.notes {
-            // Skip the note itself
-            if note.id == target_id {
-                continue;
-            }
-            // Quick pre-filter: does this note link to any of our target names?
-            let has_link = note.outlinks.iter().any(|id| target_names.contains(&id.name));
-            if !has_link {
-                continue;
-            }
-            // Re-scan to get precise positions
-            let content = match note.document.as_ref() {
-                Some(doc) => doc.get_content(None).to_string(),
-                None => continue,
-            };
-            let note_path = match &note.path {
-                Some(p) => p.clone(),
-                None => continue,
-            };
-            for (text, range) in scan_links(&content) {
-                if let Some(id) = NoteID::from_link(&text) {
-                    if target_names.contains(&id.name) {
-                        locations.push(Location {
-                            uri: Url::from_file_path(&note_path).unwrap(),
-                            range,
-                        });
-                    }
-                }
-            }
-            for (tag, range) in scan_tags(&content) {
-                let norm = crate::index::normalize_name(&tag);
-                if target_names.contains(&norm) {
-                    locations.push(Location {
-                        uri: Url::from_file_path(&note_path).unwrap(),
-                        range,
-                    });
-                }
+        let note = match index.note_at_uri(&pos.text_document.uri) {
+            Some(note) => note,
+            None => return Ok(None),
+        };
+        let target = match note
+            .document
+            .as_ref()
+            .and_then(|doc| target_at(doc.get_content(None), pos.position.line, pos.position.character))
+        {
+            Some(target) => target.id,
+            None => return Ok(None),
+        };
+
+        // Compare what links resolve to, so `[[x]]` and `[[x (note)]]` match.
+        // Links to missing notes can only be compared by name, and a link
+        // without a kind matches one with any kind.
+        let resolved = |id: &NoteID| index.resolve_link(id).map(|n| n.id.clone());
+        let target_note = resolved(&target);
+        let same = |id: &NoteID| match &target_note {
+            Some(target_note) => resolved(id).as_ref() == Some(target_note),
+            None => {
+                id.name == target.name
+                    && (id.kind == target.kind || id.kind == NoteKind::Any || target.kind == NoteKind::Any)
             }
-        }
+        };
 
-        if locations.is_empty() {
+        let highlights: Vec<DocumentHighlight> = note
+            .links
+            .iter()
+            .filter(|(id, _)| same(id))
+            .map(|(_, range)| DocumentHighlight { range: *range, kind: Some(DocumentHighlightKind::TEXT) })
+            .collect();
+        if highlights.is_empty() {
             Ok(None)
         } else {
-            Ok(Some(locations))
+            Ok(Some(highlights))
         }
     }
 
@@ -419,7 +1640,7 @@ impl LanguageServer for Backend {
         let line = params.text_document_position.position.line;
         let col = params.text_document_position.position.character as usize;
 
-        let index = self.index.lock().await;
+        let index = self.index.read().await;
 
         // Find the partial link text the user has typed after `[[`
         let line_text = if let Some(note) = index.note_at_uri(&uri) {
@@ -436,116 +1657,1243 @@ impl LanguageServer for Backend {
             return Ok(None);
         };
 
-        let (partial, partial_start) = match partial_link_at(&line_text, col) {
-            Some(p) => (p.0.to_lowercase(), p.1),
-            None => return Ok(None),
+        let (partial, partial_start) =
+            match partial_link_at(&line_text, crate::offsets::byte_col(&line_text, col as u32)) {
+            Some(p) => p,
+            None => return Ok(tag_completions(&index, &line_text, line, col as u32)),
         };
 
-        // Normalise the partial so spaces and hyphens are equivalent when matching
-        // against the hyphenated note name (e.g. "what is" matches "what-is-a-compiler").
-        let partial_norm = partial.replace(' ', "-");
+        // After `#` or `::`, offer the linked note's headings instead.
+        let (note_part, anchor) = crate::index::split_anchor(&partial);
+        if !anchor.is_empty() {
+            let target = match NoteID::from_link(note_part.trim()) {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            let note = match index.resolve_link(&target) {
+                Some(note) => note,
+                None => return Ok(None),
+            };
+            let separator = if anchor.starts_with("::") { 2 } else { 1 };
+            let anchor_start = partial_start + note_part.len() + separator;
+            let replace_range = Range::new(
+                Position::new(line, crate::offsets::utf16_col(&line_text, anchor_start)),
+                Position::new(line, col as u32),
+            );
+            let title = note.display_title();
+            let items: Vec<CompletionItem> = note
+                .headings()
+                .into_iter()
+                .map(|heading| {
+                    let opening = note.section_excerpt(&heading, 1).pop();
+                    CompletionItem {
+                        label: heading.text.clone(),
+                        kind: Some(CompletionItemKind::FIELD),
+                        detail: Some(format!("{} {}", "#".repeat(heading.level), title)),
+                        documentation: opening.map(|line| Documentation::String(line.trim().to_string())),
+                        text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                            range: replace_range,
+                            new_text: heading.text,
+                        })),
+                        ..Default::default()
+                    }
+                })
+                .collect();
+            return Ok(if items.is_empty() { None } else { Some(CompletionResponse::Array(items)) });
+        }
+
+        // Inside an unclosed `(`, offer the kinds, those a note with this
+        // name exists as first.
+        if let Some((name, typed)) = partial.rsplit_once('(')
+            && !typed.contains(')')
+        {
+            let name = crate::index::normalize_name(name);
+            let kind_start = partial_start + partial.len() - typed.len();
+            let replace_range = Range::new(
+                Position::new(line, crate::offsets::utf16_col(&line_text, kind_start)),
+                Position::new(line, col as u32),
+            );
+            let cursor = crate::offsets::byte_col(&line_text, col as u32);
+            let closed = line_text[cursor..].trim_start().starts_with(')');
+            let items = NoteKind::all()
+                .into_iter()
+                .map(|kind| {
+                    let id = NoteID { name: name.clone(), kind: kind.clone() };
+                    let exists = index.notes.contains_key(&id);
+                    CompletionItem {
+                        label: kind.to_str().to_string(),
+                        kind: Some(CompletionItemKind::ENUM_MEMBER),
+                        detail: Some(if exists {
+                            format!("exists: {}", id.to_filename().display())
+                        } else {
+                            kind.display_name()
+                        }),
+                        text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                            range: replace_range,
+                            new_text: format!("{}{}", kind.to_str(), if closed { "" } else { ")" }),
+                        })),
+                        filter_text: Some(kind.to_str().to_string()),
+                        sort_text: Some(completion_sort_text(exists, kind.to_str())),
+                        ..Default::default()
+                    }
+                })
+                .collect();
+            return Ok(Some(CompletionResponse::Array(items)));
+        }
+
+        let partial = partial.to_lowercase();
 
         // The range to replace when a completion is accepted: from right after `[[`
         // to the cursor.  This ensures the whole partially-typed text is replaced.
         let replace_range = Range::new(
-            Position::new(line, partial_start as u32),
+            Position::new(line, crate::offsets::utf16_col(&line_text, partial_start)),
             Position::new(line, col as u32),
         );
 
-        let mut items: Vec<CompletionItem> = Vec::new();
+        // (score, item), best first once sorted.
+        let mut scored: Vec<(i64, CompletionItem)> = Vec::new();
+        let shared = index.ambiguous_names();
 
         for (id, note) in &index.notes {
-            let display_name = note.title.as_deref().unwrap_or(id.name.as_str());
             // Nice label: hyphens → spaces so the popup reads naturally.
             let nice_name = id.name.replace('-', " ");
-            // Append kind suffix for non-Note kinds so the link resolves unambiguously.
+            // Append kind suffix for non-Note kinds, and for notes whose name
+            // other kinds share, so the link resolves unambiguously.
             let insert_text = match id.kind {
-                NoteKind::Note => nice_name.clone(),
+                NoteKind::Note if !shared.contains_key(&id.name) => nice_name.clone(),
                 _ => format!("{} ({})", nice_name, id.kind.to_str()),
             };
 
-            if id.name.contains(&partial_norm) || display_name.to_lowercase().contains(&partial) {
-                items.push(CompletionItem {
-                    label: insert_text.clone(),
-                    kind: Some(CompletionItemKind::FILE),
+            let score = [nice_name.as_str(), note.title.as_deref().unwrap_or("")]
+                .into_iter()
+                .filter_map(|text| fuzzy_score(&partial, text))
+                .max();
+            if let Some(score) = score {
+                scored.push((score, CompletionItem {
+                    label: nice_name.clone(),
+                    kind: Some(CompletionItemKind::REFERENCE),
+                    detail: Some(id.kind.display_name()),
                     text_edit: Some(CompletionTextEdit::Edit(TextEdit {
                         range: replace_range,
                         new_text: insert_text,
                     })),
-                    filter_text: Some(format!("{} {}", nice_name, id.name)),
                     ..Default::default()
-                });
+                }));
             }
 
             // Alias completions
             for alias in &note.aliases {
-                let alias_lower = alias.to_lowercase();
-                if alias_lower.contains(&partial) || alias_lower.replace(' ', "-").contains(&partial_norm) {
-                    items.push(CompletionItem {
+                if let Some(score) = fuzzy_score(&partial, alias) {
+                    scored.push((score, CompletionItem {
                         label: alias.clone(),
-                        kind: Some(CompletionItemKind::FILE),
+                        kind: Some(CompletionItemKind::REFERENCE),
+                        detail: Some(format!("alias of {} ({})", nice_name, id.kind.display_name())),
                         text_edit: Some(CompletionTextEdit::Edit(TextEdit {
                             range: replace_range,
                             new_text: alias.clone(),
                         })),
-                        filter_text: Some(format!("{} {}", alias, alias.replace(' ', "-"))),
                         ..Default::default()
-                    });
+                    }));
                 }
             }
         }
 
+        // The editor would otherwise re-filter and re-sort by its own rules, so
+        // every item filters as what's been typed and sorts by its rank. The
+        // list is incomplete, so each keystroke asks for a fresh ranking.
+        scored.sort_by(|(a, x), (b, y)| b.cmp(a).then_with(|| x.label.cmp(&y.label)));
+        let items: Vec<CompletionItem> = scored
+            .into_iter()
+            .enumerate()
+            .map(|(rank, (_, item))| CompletionItem {
+                filter_text: Some(partial.clone()),
+                sort_text: Some(format!("{:05}", rank)),
+                ..item
+            })
+            .collect();
+
         if items.is_empty() {
             Ok(None)
         } else {
-            Ok(Some(CompletionResponse::Array(items)))
+            Ok(Some(CompletionResponse::List(CompletionList { is_incomplete: true, items })))
         }
     }
 
-    async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        let uri = params.text_document.uri.clone();
-        let id = match NoteID::from_uri(&uri) {
+    #[allow(deprecated)]
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> Result<Option<DocumentSymbolResponse>> {
+        let index = self.index.read().await;
+
+        let note = match index.note_at_uri(&params.text_document.uri) {
+            Some(note) => note,
+            None => return Ok(None),
+        };
+
+        let headings = note.headings();
+        let has_title_heading = headings.iter().any(|h| h.level == 1);
+        let mut symbols = heading_symbols(headings, &note.tasks());
+
+        // A title set by front matter or `#+title:` has no heading to show
+        // it, so it gets a symbol of its own at the top.
+        if let Some(title) = note.title.as_ref().filter(|_| !has_title_heading) {
+            let start = Position::new(0, 0);
+            symbols.insert(
+                0,
+                DocumentSymbol {
+                    name: title.clone(),
+                    detail: Some(note.id.kind.display_name()),
+                    kind: SymbolKind::FILE,
+                    tags: None,
+                    deprecated: None,
+                    range: Range::new(start, start),
+                    selection_range: Range::new(start, start),
+                    children: None,
+                },
+            );
+        }
+
+        Ok(Some(DocumentSymbolResponse::Nested(symbols)))
+    }
+
+    #[allow(deprecated)]
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> Result<Option<Vec<SymbolInformation>>> {
+        let query = params.query.to_lowercase();
+        let index = self.index.read().await;
+
+        // A query like `#reading` finds the notes carrying a tag.
+        if let Some(tag) = query.strip_prefix('#') {
+            let tag = crate::index::normalize_name(tag);
+            let mut symbols = Vec::new();
+            for matching in index.all_tags().filter(|t| t.starts_with(&tag)) {
+                for id in index.notes_with_tag(matching) {
+                    let note = match index.notes.get(&id) {
+                        Some(note) => note,
+                        None => continue,
+                    };
+                    let uri = match index.path_of(note).and_then(|p| Url::from_file_path(p).ok()) {
+                        Some(uri) => uri,
+                        None => continue,
+                    };
+                    let start = note.title_position();
+                    symbols.push(SymbolInformation {
+                        name: note.display_title(),
+                        kind: SymbolKind::FILE,
+                        tags: None,
+                        deprecated: None,
+                        location: Location { uri, range: Range::new(start, start) },
+                        container_name: Some(format!("#{}", matching)),
+                    });
+                }
+            }
+            symbols.truncate(MAX_WORKSPACE_SYMBOLS);
+            return Ok(Some(symbols));
+        }
+
+        // (score, symbol), best first once sorted.
+        let mut scored = Vec::new();
+        for note in index.notes.values() {
+            let uri = match index.path_of(note).and_then(|p| Url::from_file_path(p).ok()) {
+                Some(uri) => uri,
+                None => continue,
+            };
+            let title = note.display_title();
+
+            let score = [note.id.name.replace('-', " "), title.clone()]
+                .iter()
+                .filter_map(|text| fuzzy_score(&query, text))
+                .max();
+            if let Some(score) = score {
+                let start = note.title_position();
+                scored.push((score, SymbolInformation {
+                    name: title.clone(),
+                    kind: SymbolKind::FILE,
+                    tags: None,
+                    deprecated: None,
+                    location: Location { uri: uri.clone(), range: Range::new(start, start) },
+                    container_name: Some(note.id.kind.display_name()),
+                }));
+            }
+
+            for heading in note.headings() {
+                if let Some(score) = fuzzy_score(&query, &heading.text) {
+                    scored.push((score, SymbolInformation {
+                        name: heading.text,
+                        kind: SymbolKind::STRING,
+                        tags: None,
+                        deprecated: None,
+                        location: Location { uri: uri.clone(), range: heading.range },
+                        container_name: Some(format!("{} ({})", title, note.id.kind.display_name())),
+                    }));
+                }
+            }
+        }
+
+        // Clients generally keep the order symbols come in, so the best
+        // matches go first.
+        scored.sort_by(|(a, x), (b, y)| b.cmp(a).then_with(|| x.name.cmp(&y.name)));
+        let symbols: Vec<SymbolInformation> = scored
+            .into_iter()
+            .take(MAX_WORKSPACE_SYMBOLS)
+            .map(|(_, symbol)| symbol)
+            .collect();
+
+        Ok(Some(symbols))
+    }
+
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<PrepareRenameResponse>> {
+        let index = self.index.read().await;
+        let note = match index.note_at_uri(&params.text_document.uri) {
+            Some(note) => note,
+            None => return Ok(None),
+        };
+        let content = match &note.document {
+            Some(doc) => doc.get_content(None),
+            None => return Ok(None),
+        };
+
+        // Only a link to an existing note, or this note's title heading, can
+        // be renamed; anywhere else the editor says it can't rename. For a
+        // link, just the name is offered, not its brackets, kind or anchor.
+        let position = params.position;
+        let (target, range) = match link_at(content, position.line, position.character) {
+            Some(link) => match (LinkTarget::from_link(&link.text), link.name_range) {
+                (Some(LinkTarget { id, .. }), Some(range)) => match index.resolve_link(&id) {
+                    Some(target) => (target, range),
+                    None => return Ok(None),
+                },
+                _ => return Ok(None),
+            },
+            None => match note.headings().into_iter().find(|h| h.level == 1) {
+                Some(title) if title.range.start.line == params.position.line => (note, title.range),
+                _ => return Ok(None),
+            },
+        };
+
+        Ok(Some(PrepareRenameResponse::RangeWithPlaceholder {
+            range,
+            placeholder: target.id.name.replace('-', " "),
+        }))
+    }
+
+    async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
+        let pos = params.text_document_position;
+        let index = self.index.read().await;
+
+        let old_id = match subject_at(&index, &pos.text_document.uri, pos.position) {
             Some(id) => id,
-            None => return,
+            None => return Ok(None),
+        };
+        let (old_path, new_id) = match index.notes.get(&old_id) {
+            Some(note) => match (index.path_of(note), NoteID::parse_link(params.new_name.trim())) {
+                // The new name is written into every link as it is, so it
+                // can't carry a kind of its own.
+                (_, Ok(new)) if new.kind != NoteKind::Any => {
+                    return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                        "'{}' has a kind; use {} to change a note's kind",
+                        params.new_name, CHANGE_KIND_COMMAND
+                    )));
+                }
+                (Some(path), Ok(new)) => (path, NoteID { name: new.name, kind: old_id.kind.clone() }),
+                (_, Err(err)) => {
+                    return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                        "'{}' is not a valid note name: {}",
+                        params.new_name, err
+                    )));
+                }
+                (None, _) => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        if new_id == old_id {
+            return Ok(None);
+        }
+        if index.notes.contains_key(&new_id) {
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "A note called '{}' already exists",
+                new_id.to_filename().display()
+            )));
+        }
+        let new_path = old_path.with_file_name(new_id.to_filename());
+        let new_name = params.new_name.trim();
+        let edit =
+            move_note_edit(&index, &old_id, &old_path, &new_path, |text| rewrite_link(text, new_name))
+                .ok_or_else(|| {
+                    tower_lsp::jsonrpc::Error::invalid_params(format!(
+                        "Can't move the note to {}",
+                        new_path.display()
+                    ))
+                })?;
+
+        // Nothing is moved in the index yet, since the editor may not apply
+        // the edit. Once it does, the move comes back through
+        // `did_rename_files`, or failing that the file watcher, and the
+        // rewritten links as edits to open notes and changes to closed ones.
+        Ok(Some(edit))
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = params.text_document.uri;
+        let index = self.index.read().await;
+
+        let note = match index.note_at_uri(&uri) {
+            Some(note) => note,
+            None => return Ok(None),
         };
+        let broken = index.broken_links(note);
+
+        // Each "note not found" diagnostic gets a fix that creates the note,
+        // through the same command as creating one by hand, and fixes that
+        // point it at notes with similar names.
+        let mut actions = Vec::new();
+        for diagnostic in &params.context.diagnostics {
+            if diagnostic.code != Some(NumberOrString::String(NOTE_NOT_FOUND.to_string())) {
+                continue;
+            }
+            let id = match broken.iter().find(|(_, range)| *range == diagnostic.range) {
+                Some((id, _)) => id,
+                None => continue,
+            };
+            let kind = if id.kind == NoteKind::Any { NoteKind::Note } else { id.kind.clone() };
+            let name = id.name.replace('-', " ");
+
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: format!("Create note '{}' ({})", name, kind.to_str()),
+                kind: Some(CodeActionKind::QUICKFIX),
+                diagnostics: Some(vec![diagnostic.clone()]),
+                command: Some(Command {
+                    title: "Create note".to_string(),
+                    command: CREATE_NOTE_COMMAND.to_string(),
+                    arguments: Some(vec![serde_json::json!({
+                        "name": name,
+                        "kind": kind.to_str(),
+                        "from": uri,
+                    })]),
+                }),
+                is_preferred: Some(true),
+                ..Default::default()
+            }));
+
+            // It may just be misspelt: offer the nearest names that exist.
+            let content = note.content();
+            let text = match text_in_range(&content, &diagnostic.range) {
+                Some(text) => text,
+                None => continue,
+            };
+            for closest in index.closest_names(id, 3) {
+                let new_text = match rewrite_link(text, &closest.replace('-', " ")) {
+                    Some(new_text) => new_text,
+                    None => continue,
+                };
+                actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                    title: format!("Change to {}", new_text),
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(vec![diagnostic.clone()]),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some(
+                            [(uri.clone(), vec![TextEdit { range: diagnostic.range, new_text }])]
+                                .into_iter()
+                                .collect(),
+                        ),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }));
+            }
+        }
+
+        // On a heading, offer to change its TODO keyword.
+        if let Some(doc) = &note.document {
+            let row = params.range.start.line as usize;
+            for (title, edit) in todo_keyword_edits(doc.get_content(None), row) {
+                actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                    title,
+                    kind: Some(CodeActionKind::REFACTOR_REWRITE),
+                    edit: Some(WorkspaceEdit {
+                        changes: Some([(uri.clone(), vec![edit])].into_iter().collect()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }));
+            }
+        }
+
+        // On any heading but the title, offer to move its section out.
+        let row = params.range.start.line;
+        let headings = note.headings();
+        let is_title =
+            headings.iter().find(|h| h.level == 1).is_some_and(|h| h.range.start.line == row);
+        if !is_title && headings.iter().any(|h| h.range.start.line == row) {
+            actions.push(CodeActionOrCommand::CodeAction(CodeAction {
+                title: "Extract subtree to new note".to_string(),
+                kind: Some(CodeActionKind::REFACTOR_EXTRACT),
+                command: Some(Command {
+                    title: "Extract subtree".to_string(),
+                    command: EXTRACT_SUBTREE_COMMAND.to_string(),
+                    arguments: Some(vec![serde_json::json!({ "uri": uri, "line": row })]),
+                }),
+                ..Default::default()
+            }));
+        }
+
+        actions.extend(link_selection_actions(&index, note, &uri, &params.range));
+
+        if actions.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(actions))
+        }
+    }
 
+    async fn did_create_files(&self, params: CreateFilesParams) {
+        let mut created = Vec::new();
         {
-            let mut index = self.index.lock().await;
-            let note = index.notes.entry(id.clone()).or_insert_with(|| Note::new(id));
-            note.document = Some(lsp_textdocument::FullTextDocument::new(
-                String::from("noteboks"),
-                params.text_document.version,
-                params.text_document.text,
-            ));
-            note.update_links();
+            let mut index = self.index.write().await;
+            for file in params.files {
+                let path = match Url::parse(&file.uri).ok().and_then(|u| u.to_file_path().ok()) {
+                    Some(path) => path,
+                    None => continue,
+                };
+                if let Some(note) = Note::of_file(&path)
+                    && !index.notes.get(&note.id).is_some_and(|n| n.open)
+                {
+                    created.push(note.id.clone());
+                    index.insert_note(note);
+                }
+            }
         }
 
-        self.publish_all_diagnostics().await;
+        // Links to the new notes are no longer broken.
+        for id in created {
+            self.publish_dependent_diagnostics(id).await;
+        }
     }
 
-    async fn did_save(&self, params: DidSaveTextDocumentParams) {
-        let uri = params.text_document.uri.clone();
+    async fn will_rename_files(&self, params: RenameFilesParams) -> Result<Option<WorkspaceEdit>> {
+        let index = self.index.read().await;
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for file in &params.files {
+            let id_of = |uri: &str| Url::parse(uri).ok().and_then(|u| NoteID::from_uri(&u));
+            let (old_id, new_id) = match (id_of(&file.old_uri), id_of(&file.new_uri)) {
+                (Some(old_id), Some(new_id)) if old_id != new_id => (old_id, new_id),
+                _ => continue,
+            };
+            if !index.notes.contains_key(&old_id) {
+                continue;
+            }
+
+            // The link text takes the new name from the file name, and any
+            // `(kind)` or extension it has follows the new one. A bare link
+            // gets the kind once another note shares the name, or it would
+            // stop reaching this one.
+            let shared = index.notes.keys().any(|id| id.name == new_id.name && *id != old_id);
+            let new_name = new_id.name.replace('-', " ");
+            let rewrite = |text: &str| {
+                let renamed = rewrite_link(text, &new_name)?;
+                let bare =
+                    text.starts_with("[[") && rewrite_link_kind(text, &new_id.kind).is_none();
+                if bare && shared {
+                    return rewrite_link(text, &crate::index::link_text(&new_name, &new_id.kind));
+                }
+                Some(rewrite_link_kind(&renamed, &new_id.kind).unwrap_or(renamed))
+            };
+            for (uri, edits) in backlink_edits(&index, &old_id, rewrite) {
+                changes.entry(uri).or_default().extend(edits);
+            }
+        }
+
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(WorkspaceEdit { changes: Some(changes), ..Default::default() }))
+    }
 
-        // Re-read from disk in case this is a newly created file
-        if let Some(path) = uri.to_file_path().ok() {
-            if let Some(note) = crate::index::Note::of_file(&path) {
-                let mut index = self.index.lock().await;
-                index.notes.insert(note.id.clone(), note);
+    async fn did_rename_files(&self, params: RenameFilesParams) {
+        let (mut renamed, mut names) = (Vec::new(), Vec::new());
+        {
+            let mut index = self.index.write().await;
+            for file in params.files {
+                let path_of = |uri: &str| Url::parse(uri).ok().and_then(|u| u.to_file_path().ok());
+                let old_id = match path_of(&file.old_uri).and_then(|path| NoteID::from_path(&path)) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                names.extend(index.link_names(&old_id));
+                let new_path = path_of(&file.new_uri);
+                match new_path.and_then(|path| Some((NoteID::from_path(&path)?, path))) {
+                    Some((new_id, path)) => {
+                        names.extend(index.link_names(&new_id));
+                        renamed.push(new_id.clone());
+                        index.rename_note(&old_id, new_id, path);
+                    }
+                    // Renamed to something that isn't a note any more.
+                    None => {
+                        index.remove_note(&old_id);
+                    }
+                }
             }
         }
 
-        self.publish_all_diagnostics().await;
+        // Links to the old names are broken now, and those to the new ones
+        // resolve.
+        self.publish_linking_diagnostics(renamed, &names).await;
+    }
+
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        match params.command.as_str() {
+            CREATE_NOTE_COMMAND => {
+                let id = note_id_arg(params.arguments.first())
+                    .map_err(tower_lsp::jsonrpc::Error::invalid_params)?;
+                // The note goes in the same vault as the one asking for it,
+                // if the request says which that is.
+                let near = params
+                    .arguments
+                    .first()
+                    .and_then(|a| a.get("from"))
+                    .and_then(|u| u.as_str())
+                    .and_then(|u| Url::parse(u).ok())
+                    .and_then(|u| u.to_file_path().ok());
+                let created = self.index.write().await.create_note(id.clone(), near.as_deref());
+                match created {
+                    Ok(path) => {
+                        self.publish_dependent_diagnostics(id).await;
+                        Ok(Url::from_file_path(path)
+                            .ok()
+                            .map(|uri| serde_json::Value::String(uri.to_string())))
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                        Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                            "A note called '{}' already exists",
+                            id.to_filename().display()
+                        )))
+                    }
+                    Err(err) => Err(tower_lsp::jsonrpc::Error {
+                        code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                        message: format!("Couldn't create note: {}", err).into(),
+                        data: None,
+                    }),
+                }
+            }
+            INSERT_LINK_COMMAND => {
+                let id = note_id_arg(params.arguments.first())
+                    .map_err(tower_lsp::jsonrpc::Error::invalid_params)?;
+                let position: TextDocumentPositionParams = params
+                    .arguments
+                    .get(1)
+                    .and_then(|arg| serde_json::from_value(arg.clone()).ok())
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params("Expected a text document position")
+                    })?;
+
+                // Leave the kind out when a bare link would find the same note.
+                let link = {
+                    let index = self.index.read().await;
+                    let bare = NoteID { name: id.name.clone(), kind: NoteKind::Any };
+                    match index.resolve_link(&bare) {
+                        Some(note) if note.id == id => bare.to_link(),
+                        _ => id.to_link(),
+                    }
+                };
+                let edit = WorkspaceEdit {
+                    changes: Some(
+                        [(
+                            position.text_document.uri,
+                            vec![TextEdit {
+                                range: Range::new(position.position, position.position),
+                                new_text: link,
+                            }],
+                        )]
+                        .into_iter()
+                        .collect(),
+                    ),
+                    ..Default::default()
+                };
+                let applied = self.client.apply_edit(edit).await?.applied;
+                Ok(Some(serde_json::Value::Bool(applied)))
+            }
+            CHANGE_KIND_COMMAND => {
+                let arg = params.arguments.first();
+                let old_id = note_id_arg(arg).map_err(tower_lsp::jsonrpc::Error::invalid_params)?;
+                let new_kind = match arg.and_then(|a| a.get("newKind")).and_then(|k| k.as_str()) {
+                    Some(kind) => NoteKind::from_str(kind).ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params(format!("Unknown note kind '{}'", kind))
+                    })?,
+                    None => return Err(tower_lsp::jsonrpc::Error::invalid_params("Expected a newKind")),
+                };
+                let new_id = NoteID { name: old_id.name.clone(), kind: new_kind.clone() };
+
+                let (edit, new_path) = {
+                    let index = self.index.read().await;
+                    let old_path = match index.notes.get(&old_id).and_then(|note| index.path_of(note)) {
+                        Some(path) => path,
+                        None => {
+                            return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                                "There's no note called '{}'",
+                                old_id.to_filename().display()
+                            )));
+                        }
+                    };
+                    if new_id == old_id {
+                        return Ok(Some(serde_json::Value::Bool(true)));
+                    }
+                    if index.notes.contains_key(&new_id) {
+                        return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                            "A note called '{}' already exists",
+                            new_id.to_filename().display()
+                        )));
+                    }
+                    let new_path = old_path.with_file_name(new_id.to_filename());
+                    let edit = move_note_edit(&index, &old_id, &old_path, &new_path, |text| {
+                        rewrite_link_kind(text, &new_kind)
+                    })
+                    .ok_or_else(|| {
+                        tower_lsp::jsonrpc::Error::invalid_params(format!(
+                            "Can't move the note to {}",
+                            new_path.display()
+                        ))
+                    })?;
+                    (edit, new_path)
+                };
+
+                // The index isn't locked while the editor applies the edit,
+                // since it sends the changes straight back.
+                let applied = self.client.apply_edit(edit).await?.applied;
+                if applied {
+                    self.index.write().await.rename_note(&old_id, new_id.clone(), new_path);
+                    self.publish_dependent_diagnostics(new_id).await;
+                }
+                Ok(Some(serde_json::Value::Bool(applied)))
+            }
+            EXTRACT_SUBTREE_COMMAND => {
+                let arg = params.arguments.first();
+                let uri = arg
+                    .and_then(|a| a.get("uri"))
+                    .and_then(|u| u.as_str())
+                    .and_then(|u| Url::parse(u).ok());
+                let row = arg.and_then(|a| a.get("line")).and_then(|l| l.as_u64());
+                let (uri, row) = match (uri, row) {
+                    (Some(uri), Some(row)) => (uri, row as u32),
+                    _ => {
+                        return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                            "Expected the uri and line of a heading",
+                        ));
+                    }
+                };
+
+                // The new note is indexed as soon as it's written, so the
+                // link that replaces the section resolves straight away.
+                let (id, path, edit) = {
+                    let mut index = self.index.write().await;
+                    let note = match index.note_at_uri(&uri) {
+                        Some(note) => note,
+                        None => return Ok(None),
+                    };
+                    let (id, content, range) = match extract_subtree(note, row) {
+                        Some(extracted) => extracted,
+                        None => {
+                            return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                                "There's no heading to extract on that line",
+                            ));
+                        }
+                    };
+                    let near = note.path.clone();
+                    let path = match index.create_note_with(id.clone(), content, near.as_deref()) {
+                        Ok(path) => path,
+                        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                            return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                                "A note called '{}' already exists",
+                                id.to_filename().display()
+                            )));
+                        }
+                        Err(err) => {
+                            return Err(tower_lsp::jsonrpc::Error {
+                                code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                                message: format!("Couldn't create note: {}", err).into(),
+                                data: None,
+                            });
+                        }
+                    };
+
+                    // Leave the kind out when a bare link would find the note.
+                    let bare = NoteID { name: id.name.clone(), kind: NoteKind::Any };
+                    let link = match index.resolve_link(&bare) {
+                        Some(found) if found.id == id => bare.to_link(),
+                        _ => id.to_link(),
+                    };
+                    let edit = WorkspaceEdit {
+                        changes: Some(
+                            [(uri, vec![TextEdit { range, new_text: link }])].into_iter().collect(),
+                        ),
+                        ..Default::default()
+                    };
+                    (id, path, edit)
+                };
+
+                // If the section can't be replaced, it would end up in both
+                // notes, so the new one goes again.
+                let applied = self.client.apply_edit(edit).await.is_ok_and(|r| r.applied);
+                if !applied {
+                    self.index.write().await.remove_note(&id);
+                    let _ = std::fs::remove_file(&path);
+                    return Err(tower_lsp::jsonrpc::Error {
+                        code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                        message: "The editor didn't replace the section, so nothing was extracted".into(),
+                        data: None,
+                    });
+                }
+                self.publish_dependent_diagnostics(id).await;
+                Ok(Url::from_file_path(path)
+                    .ok()
+                    .map(|uri| serde_json::Value::String(uri.to_string())))
+            }
+            OPEN_DAILY_NOTE_COMMAND => {
+                let settings = self.settings.lock().await.clone();
+                let date = Date::today();
+                let opened = self.index.write().await.open_daily_note(
+                    date,
+                    &settings.daily_note_format,
+                    settings.daily_note_template.as_deref(),
+                );
+                match opened {
+                    Ok(path) => {
+                        let id = Index::daily_note_id(date, &settings.daily_note_format);
+                        self.publish_dependent_diagnostics(id).await;
+                        Ok(Url::from_file_path(path)
+                            .ok()
+                            .map(|uri| serde_json::Value::String(uri.to_string())))
+                    }
+                    Err(err) => Err(tower_lsp::jsonrpc::Error {
+                        code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                        message: format!("Couldn't create daily note: {}", err).into(),
+                        data: None,
+                    }),
+                }
+            }
+            EXPORT_GRAPH_COMMAND => {
+                let arg = params.arguments.first();
+                let include_broken = arg
+                    .and_then(|a| a.get("includeBroken"))
+                    .and_then(|b| b.as_bool())
+                    .unwrap_or(false);
+                let format = arg.and_then(|a| a.get("format")).and_then(|f| f.as_str());
+                let path = arg.and_then(|a| a.get("path")).and_then(|p| p.as_str());
+
+                let index = self.index.read().await;
+                let graph = Graph::of_index(&index);
+                let rendered = match format {
+                    Some("json") => graph.to_json(include_broken).to_string(),
+                    Some("dot") | None => graph.to_dot(include_broken),
+                    Some(other) => {
+                        return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                            "Unknown graph format '{}'; expected 'json' or 'dot'",
+                            other
+                        )));
+                    }
+                };
+                if format.is_some() && path.is_none() {
+                    return Ok(Some(serde_json::Value::String(rendered)));
+                }
+
+                let path = match (path, index.default_root()) {
+                    (Some(path), Some(root)) => root.join(path),
+                    (Some(path), None) => PathBuf::from(path),
+                    (None, Some(root)) => root.join("graph.dot"),
+                    (None, None) => {
+                        return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                            "No vault root is set, so a path is needed",
+                        ));
+                    }
+                };
+                drop(index);
+
+                match std::fs::write(&path, rendered) {
+                    Ok(()) => Ok(Url::from_file_path(path)
+                        .ok()
+                        .map(|uri| serde_json::Value::String(uri.to_string()))),
+                    Err(err) => Err(tower_lsp::jsonrpc::Error {
+                        code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+                        message: format!("Couldn't write graph: {}", err).into(),
+                        data: None,
+                    }),
+                }
+            }
+            RESCAN_COMMAND => {
+                let roots = self.index.read().await.roots.clone();
+                self.scan(roots, true).await;
+                // Like the first scan, this is a fresh start, so every note's
+                // diagnostics are worked out again.
+                self.publish_all_diagnostics().await;
+                Ok(None)
+            }
+            LIST_ORPHANS_COMMAND | FIND_ORPHANS_COMMAND => {
+                let index = self.index.read().await;
+                let orphans = index
+                    .orphans()
+                    .into_iter()
+                    .filter_map(|id| {
+                        let note = index.notes.get(&id)?;
+                        let uri = index.path_of(note).and_then(|p| Url::from_file_path(p).ok());
+                        Some(serde_json::json!({
+                            "name": id.name,
+                            "kind": id.kind.to_str(),
+                            "uri": uri.map(|u| u.to_string()),
+                        }))
+                    })
+                    .collect();
+                Ok(Some(serde_json::Value::Array(orphans)))
+            }
+            LIST_CYCLES_COMMAND => {
+                let index = self.index.read().await;
+                let cycles = index
+                    .cycles()
+                    .into_iter()
+                    .map(|cycle| {
+                        cycle
+                            .iter()
+                            .map(|id| serde_json::json!({ "name": id.name, "kind": id.kind.to_str() }))
+                            .collect()
+                    })
+                    .collect();
+                Ok(Some(serde_json::Value::Array(cycles)))
+            }
+            PATH_BETWEEN_COMMAND => {
+                let from = note_id_arg(params.arguments.first())
+                    .map_err(tower_lsp::jsonrpc::Error::invalid_params)?;
+                let to = note_id_arg(params.arguments.get(1))
+                    .map_err(tower_lsp::jsonrpc::Error::invalid_params)?;
+                let undirected = params
+                    .arguments
+                    .get(2)
+                    .and_then(|a| a.get("undirected"))
+                    .and_then(|u| u.as_bool())
+                    .unwrap_or(false);
+
+                let index = self.index.read().await;
+                for id in [&from, &to] {
+                    if !index.notes.contains_key(id) {
+                        return Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                            "There's no note called '{}'",
+                            id.to_filename().display()
+                        )));
+                    }
+                }
+                Ok(Some(match index.path_between(&from, &to, undirected) {
+                    Some(path) => path
+                        .iter()
+                        .map(|id| serde_json::json!({ "name": id.name, "kind": id.kind.to_str() }))
+                        .collect(),
+                    None => serde_json::Value::Null,
+                }))
+            }
+            LIST_TASKS_COMMAND => {
+                let index = self.index.read().await;
+                let mut tasks = Vec::new();
+                for note in index.notes.values() {
+                    let uri = match index.path_of(note).and_then(|p| Url::from_file_path(p).ok()) {
+                        Some(uri) => uri,
+                        None => continue,
+                    };
+                    for task in note.tasks().into_iter().filter(|t| !t.done) {
+                        tasks.push(serde_json::json!({
+                            "location": Location { uri: uri.clone(), range: task.range },
+                            "text": task.text,
+                            "state": task.state,
+                        }));
+                    }
+                }
+                Ok(Some(serde_json::Value::Array(tasks)))
+            }
+            LIST_BACKLINKS_COMMAND => {
+                let uri = match params
+                    .arguments
+                    .first()
+                    .and_then(|a| a.get("uri"))
+                    .and_then(|u| u.as_str())
+                    .and_then(|u| Url::parse(u).ok())
+                {
+                    Some(uri) => uri,
+                    None => return Err(tower_lsp::jsonrpc::Error::invalid_params("Expected the uri of a note")),
+                };
+                let index = self.index.read().await;
+                let locations = match index.note_at_uri(&uri) {
+                    Some(note) => backlink_locations(&index, &note.id),
+                    None => return Ok(None),
+                };
+                Ok(Some(serde_json::json!(locations)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        // Notes whose diagnostics could have changed: the changed notes
+        // themselves, and anything linking to them.
+        let mut affected = Vec::new();
+        {
+            let mut index = self.index.write().await;
+            for change in params.changes {
+                let path = match change.uri.to_file_path() {
+                    Ok(path) => path,
+                    Err(_) => continue,
+                };
+                let id = match NoteID::from_path(&path) {
+                    Some(id) => id,
+                    None => continue,
+                };
+                // An open buffer is authoritative over what's on disk.
+                if index.notes.get(&id).is_some_and(|n| n.open) {
+                    continue;
+                }
+
+                if change.typ == FileChangeType::DELETED {
+                    affected.extend(index.backlinks_to(&id).into_keys());
+                    index.remove_note(&id);
+                } else if let Some(note) = Note::of_file(&path) {
+                    index.insert_note(note);
+                    affected.extend(index.backlinks_to(&id).into_keys());
+                    affected.push(id);
+                }
+            }
+            affected.retain(|id| index.notes.contains_key(id));
+            affected.sort();
+            affected.dedup();
+        }
+
+        self.publish_diagnostics_for(affected).await;
+    }
+
+    async fn document_link(&self, params: DocumentLinkParams) -> Result<Option<Vec<DocumentLink>>> {
+        let index = self.index.read().await;
+        let content = match index
+            .note_at_uri(&params.text_document.uri)
+            .and_then(|n| n.document.as_ref())
+        {
+            Some(doc) => doc.get_content(None),
+            None => return Ok(None),
+        };
+
+        let mut links: Vec<DocumentLink> = scan_links(content)
+            .into_iter()
+            .filter_map(|(text, range)| {
+                let LinkTarget { id, anchor } = LinkTarget::from_link(&text)?;
+                let note = index.resolve_link(&id);
+                let mut target = note
+                    .and_then(|n| index.path_of(n))
+                    .and_then(|p| Url::from_file_path(p).ok());
+                // Point links with an anchor at the heading's line.
+                if let (Some(uri), Some(heading)) =
+                    (target.as_mut(), note.zip(anchor.as_deref()).and_then(|(n, a)| n.heading(a)))
+                {
+                    uri.set_fragment(Some(&format!("L{}", heading.range.start.line + 1)));
+                }
+                let tooltip = match target {
+                    Some(_) => None,
+                    None => Some(format!("No note named '{}' found", id.name)),
+                };
+                Some(DocumentLink { range, target, tooltip, data: None })
+            })
+            .collect();
+
+        // Tags only become links when a note by that name exists.
+        for (tag, range) in crate::index::scan_tags(content) {
+            if links.iter().any(|l| ranges_overlap(&l.range, &range)) {
+                continue;
+            }
+            let id = NoteID { name: crate::index::normalize_name(&tag), kind: NoteKind::Any };
+            if let Some(uri) = index
+                .resolve_link(&id)
+                .and_then(|n| index.path_of(n))
+                .and_then(|p| Url::from_file_path(p).ok())
+            {
+                links.push(DocumentLink { range, target: Some(uri), tooltip: None, data: None });
+            }
+        }
+
+        Ok(Some(links))
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        let index = self.index.read().await;
+        let note = match index.note_at_uri(&params.text_document.uri) {
+            Some(note) => note,
+            None => return Ok(None),
+        };
+        let content = match note.document.as_ref() {
+            Some(doc) => doc.get_content(None),
+            None => return Ok(None),
+        };
+
+        let fold = |start: u32, end: u32, kind: FoldingRangeKind| FoldingRange {
+            start_line: start,
+            end_line: end,
+            kind: Some(kind),
+            ..Default::default()
+        };
+
+        let mut ranges = Vec::new();
+        if let Some((start, end)) = crate::index::front_matter_lines(content) {
+            ranges.push(fold(start, end, FoldingRangeKind::Comment));
+        }
+        for heading in note.headings() {
+            if heading.section.end.line > heading.section.start.line {
+                ranges.push(fold(heading.section.start.line, heading.section.end.line, FoldingRangeKind::Region));
+            }
+        }
+        let blocks = crate::index::scan_code_blocks(content)
+            .into_iter()
+            .chain(crate::index::scan_block_quotes(content));
+        for (start, end) in blocks {
+            if end > start {
+                ranges.push(fold(start, end, FoldingRangeKind::Region));
+            }
+        }
+
+        Ok(Some(ranges))
+    }
+
+    async fn code_lens(&self, params: CodeLensParams) -> Result<Option<Vec<CodeLens>>> {
+        let uri = params.text_document.uri;
+        let index = self.index.read().await;
+        let note = match index.note_at_uri(&uri) {
+            Some(note) => note,
+            None => return Ok(None),
+        };
+
+        let locations = backlink_locations(&index, &note.id);
+        let start = note.title_position();
+        let title = match locations.len() {
+            1 => "1 backlink".to_string(),
+            n => format!("{} backlinks", n),
+        };
+        Ok(Some(vec![CodeLens {
+            range: Range::new(start, start),
+            command: Some(Command {
+                title,
+                command: LIST_BACKLINKS_COMMAND.to_string(),
+                arguments: Some(vec![serde_json::json!({ "uri": uri })]),
+            }),
+            data: None,
+        }]))
+    }
+
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> Result<Option<SemanticTokensResult>> {
+        let index = self.index.read().await;
+        let content = match index
+            .note_at_uri(&params.text_document.uri)
+            .and_then(|n| n.document.as_ref())
+        {
+            Some(doc) => doc.get_content(None),
+            None => return Ok(None),
+        };
+
+        Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: None,
+            data: semantic_tokens::tokens(content, |text| {
+                LinkTarget::from_link(text).is_some_and(|t| index.resolve_link(&t.id).is_some())
+            }),
+        })))
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let id = match NoteID::from_uri(&params.text_document.uri) {
+            Some(id) => id,
+            None => return,
+        };
+        if self.index.write().await.handle_open(params.text_document) {
+            self.publish_dependent_diagnostics(id).await;
+        }
+    }
+
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let uri = params.text_document.uri.clone();
+        let id = match NoteID::from_uri(&uri) {
+            Some(id) => id,
+            None => return,
+        };
+
+        self.index.write().await.handle_save(&uri, params.text);
+
+        // Links to this note elsewhere may have started (or stopped) resolving.
+        self.publish_dependent_diagnostics(id).await;
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+        let (removed, names) = {
+            let mut index = self.index.write().await;
+            let names = NoteID::from_uri(&uri).map(|id| index.link_names(&id)).unwrap_or_default();
+            (index.handle_close(&uri), names)
+        };
+        // Any pending refresh would publish diagnostics for a closed note.
+        self.edit_counts.lock().await.remove(&uri);
+
+        // The editor no longer shows this note, so its diagnostics go too.
+        self.client.publish_diagnostics(uri.clone(), vec![], None).await;
+
+        if removed {
+            self.client
+                .log_message(MessageType::INFO, format!("Closed {}, which no longer exists on disk", uri))
+                .await;
+            // Links to it are broken now.
+            self.publish_linking_diagnostics(vec![], &names).await;
+        } else {
+            self.client
+                .log_message(MessageType::INFO, format!("Closed {}, reloaded from disk", uri))
+                .await;
+        }
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
         let uri = params.text_document.uri.clone();
 
-        self.index
-            .lock()
+        // The text is updated straight away, so hovers and the like are
+        // accurate, but links and diagnostics wait until typing pauses.
+        let id = match self
+            .index
+            .write()
             .await
-            .handle_edit(params.text_document, params.content_changes);
+            .handle_edit(params.text_document, params.content_changes)
+        {
+            Some(id) => id,
+            None => return,
+        };
+
+        let count = {
+            let mut counts = self.edit_counts.lock().await;
+            let count = counts.entry(uri.clone()).or_insert(0);
+            *count += 1;
+            *count
+        };
 
-        self.publish_diagnostics(uri).await;
+        let backend = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(EDIT_DEBOUNCE).await;
+            if backend.edit_counts.lock().await.get(&uri) != Some(&count) {
+                return;
+            }
+
+            let links_changed = backend.index.write().await.refresh_links(&id);
+            backend.publish_diagnostics(uri).await;
+
+            // Backlink counts shown on other notes may be out of date. Clients
+            // that can't refresh code lenses just return an error, which is fine.
+            if links_changed {
+                let _ = backend.client.code_lens_refresh().await;
+            }
+        });
     }
 
     async fn shutdown(&self) -> Result<()> {
@@ -558,17 +2906,386 @@ async fn main() {
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let default_root = std::env::var("NOTEBOKS_VAULT")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| PathBuf::from("/Users/zacgarby/Documents/Vault"));
+    crate::index::compile_patterns();
+    semantic_tokens::compile_patterns();
+    selection::compile_patterns();
+    formatting::compile_patterns();
 
-    let index = Index::new(&default_root);
+    // The root is filled in by `initialize`, once the client has told us it.
+    let index = Index::new();
 
-    let (service, socket) = LspService::new(|client| Backend {
+    let (service, socket) = LspService::build(|client| Backend {
         client,
-        index: Arc::new(Mutex::new(index)),
-        scan_started: Arc::new(AtomicBool::new(false))
-    });
+        index: Arc::new(RwLock::new(index)),
+        settings: Arc::new(Mutex::new(Settings::default())),
+        scan_started: Arc::new(AtomicBool::new(false)),
+        progress_supported: Arc::new(AtomicBool::new(false)),
+        watchers_supported: Arc::new(AtomicBool::new(false)),
+        edit_counts: Arc::new(Mutex::new(HashMap::new())),
+    })
+    .custom_method("noteboks/graph", Backend::graph)
+    .finish();
 
     Server::new(stdin, stdout, socket).serve(service).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsp_textdocument::FullTextDocument;
+    use tower_lsp::ClientSocket;
+
+    /// A note at `/vault/<name>.note`, as if the editor had it open.
+    fn note(name: &str, content: &str) -> Note {
+        let mut note = Note::new(NoteID { name: name.to_string(), kind: NoteKind::Note });
+        note.path = Some(PathBuf::from(format!("/vault/{}.note", name)));
+        note.document = Some(FullTextDocument::new(String::from("noteboks"), 0, content.to_string()));
+        note.update_links();
+        note
+    }
+
+    fn uri(name: &str) -> Url {
+        Url::from_file_path(format!("/vault/{}.note", name)).unwrap()
+    }
+
+    /// A server over an index of `notes`, with no client on the other end.
+    fn backend(notes: &[(&str, &str)]) -> (LspService<Backend>, ClientSocket) {
+        let mut index = Index::new();
+        index.add_root(PathBuf::from("/vault"));
+        for (name, content) in notes {
+            index.insert_note(note(name, content));
+        }
+        serve(index)
+    }
+
+    /// A fresh vault on disk holding `files`, named after the test using it.
+    fn vault(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("noteboks-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        for (file, content) in files {
+            std::fs::write(root.join(file), content).unwrap();
+        }
+        root
+    }
+
+    fn serve(index: Index) -> (LspService<Backend>, ClientSocket) {
+        LspService::new(|client| Backend {
+            client,
+            index: Arc::new(RwLock::new(index)),
+            settings: Arc::new(Mutex::new(Settings::default())),
+            scan_started: Arc::new(AtomicBool::new(false)),
+            progress_supported: Arc::new(AtomicBool::new(false)),
+            watchers_supported: Arc::new(AtomicBool::new(false)),
+            edit_counts: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    fn position(name: &str, line: u32, character: u32) -> TextDocumentPositionParams {
+        TextDocumentPositionParams {
+            text_document: TextDocumentIdentifier { uri: uri(name) },
+            position: Position::new(line, character),
+        }
+    }
+
+    #[test]
+    fn link_after_multibyte_text() {
+        let content = "# Notes\nçà 日本 😀 [[lambda calculus]] done";
+        // Column 9 is the first `[`, counted in UTF-16 units.
+        let link = link_at(content, 1, 9).expect("the cursor is on the link");
+        assert_eq!(link.text, "lambda calculus");
+        assert_eq!(link.range, Range::new(Position::new(1, 9), Position::new(1, 28)));
+        assert!(link_at(content, 1, 8).is_none());
+        assert!(link_at(content, 1, 28).is_none());
+    }
+
+    #[test]
+    fn link_on_missing_line() {
+        let content = "one line [[link]]";
+        assert!(link_at(content, 1, 0).is_none());
+        assert!(link_at(content, u32::MAX, u32::MAX).is_none());
+        assert!(link_at("", 0, 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn hover_past_last_line() {
+        let (service, _socket) = backend(&[("a", "# A\nSee [[b]].")]);
+        for (line, character) in [(2, 0), (100, 3), (u32::MAX, u32::MAX)] {
+            let hover = service
+                .inner()
+                .hover(HoverParams {
+                    text_document_position_params: position("a", line, character),
+                    work_done_progress_params: Default::default(),
+                })
+                .await
+                .unwrap();
+            assert!(hover.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn fuzzy_completion_ranks_closest_first() {
+        let (service, _socket) = backend(&[
+            ("recipes", "# Recipes\n"),
+            ("reciprocal-space", "# Reciprocal space\n"),
+            ("rust-compiler-plugins", "# Rust compiler plugins\n"),
+            ("cooking", "# Cooking\n"),
+            ("draft", "# Draft\n[[rcp\n"),
+        ]);
+        let completions = service
+            .inner()
+            .completion(CompletionParams {
+                text_document_position: position("draft", 1, 5),
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                context: None,
+            })
+            .await
+            .unwrap();
+        let items = match completions {
+            Some(CompletionResponse::List(list)) => list.items,
+            other => panic!("expected a completion list, got {:?}", other),
+        };
+        let labels: Vec<&str> = items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(labels.first(), Some(&"recipes"));
+        assert!(!labels.contains(&"cooking"));
+        // The editor keeps the server's order.
+        assert_eq!(items[0].sort_text.as_deref(), Some("00000"));
+    }
+
+    #[test]
+    fn completes_second_headline_tag() {
+        let mut index = Index::new();
+        index.insert_note(note("a", "# A\n#foo #bar\n"));
+        index.insert_note(note("b", "# B\n#baz\n"));
+
+        let line = "## Plans :foo:";
+        let items = match tag_completions(&index, line, 3, 14) {
+            Some(CompletionResponse::Array(items)) => items,
+            other => panic!("expected tag completions, got {:?}", other),
+        };
+        let mut edits: Vec<(String, TextEdit)> = items
+            .into_iter()
+            .map(|item| match item.text_edit {
+                Some(CompletionTextEdit::Edit(edit)) => (item.label, edit),
+                other => panic!("expected a plain edit, got {:?}", other),
+            })
+            .collect();
+        edits.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // `foo` is already on the headline, and each tag closes its group.
+        let at_cursor = Range::new(Position::new(3, 14), Position::new(3, 14));
+        assert_eq!(
+            edits,
+            [
+                ("bar".to_string(), TextEdit { range: at_cursor, new_text: "bar:".to_string() }),
+                ("baz".to_string(), TextEdit { range: at_cursor, new_text: "baz:".to_string() }),
+            ]
+        );
+    }
+
+    /// The titles of `todo_keyword_edits` for a line, and the line as each
+    /// would leave it.
+    fn todo_results(content: &str, row: usize) -> Vec<(String, String)> {
+        let line = content.lines().nth(row).unwrap();
+        todo_keyword_edits(content, row)
+            .into_iter()
+            .map(|(title, edit)| {
+                let bytes = crate::offsets::byte_range(line, &edit.range);
+                let edited = format!("{}{}{}", &line[..bytes.start], edit.new_text, &line[bytes.end..]);
+                (title, edited)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn todo_keyword_on_plain_headline() {
+        let results = todo_results("# Trip\n\n## Book hotel :travel:work:\n", 2);
+        assert_eq!(
+            results,
+            [
+                ("Set TODO".to_string(), "## TODO Book hotel :travel:work:".to_string()),
+                ("Set DONE".to_string(), "## DONE Book hotel :travel:work:".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn todo_keyword_already_set() {
+        let results = todo_results("###   TODO  Book hotel :travel:\n", 0);
+        assert_eq!(
+            results,
+            [
+                ("Set DONE".to_string(), "###   DONE Book hotel :travel:".to_string()),
+                ("Remove keyword".to_string(), "###   Book hotel :travel:".to_string()),
+            ]
+        );
+
+        let results = todo_results("#+TODO: NEXT | DONE GAVE-UP\n## GAVE-UP Book hotel\n", 1);
+        assert_eq!(
+            results,
+            [
+                ("Set NEXT".to_string(), "## NEXT Book hotel".to_string()),
+                ("Set DONE".to_string(), "## DONE Book hotel".to_string()),
+                ("Remove keyword".to_string(), "## Book hotel".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn todo_keyword_needs_a_headline() {
+        assert!(todo_results("Just text :tag:\n", 0).is_empty());
+        assert!(todo_results("#hashtag\n", 0).is_empty());
+        assert!(todo_results("```\n# not a heading\n```\n", 1).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn hovers_during_scan() {
+        let mut files = vec![
+            ("a.note".to_string(), "# A\nSee [[b]].\n".to_string()),
+            ("b.note".to_string(), "# B\nThe target.\n".to_string()),
+        ];
+        for i in 0..1000 {
+            files.push((format!("scanned-{}.note", i), "# Scanned\n[[b]] [[a]]\n".to_string()));
+        }
+        let files: Vec<(&str, &str)> = files.iter().map(|(f, c)| (f.as_str(), c.as_str())).collect();
+        let root = vault("hovers", &files);
+        let mut index = Index::new();
+        index.add_root(root.clone());
+        for file in ["a.note", "b.note"] {
+            index.insert_note(Note::of_file(&root.join(file)).unwrap());
+        }
+        let (service, _socket) = serve(index);
+        let backend = service.inner().clone();
+        let a = Url::from_file_path(root.join("a.note")).unwrap();
+
+        let scan = {
+            let backend = backend.clone();
+            let root = root.clone();
+            tokio::spawn(async move { backend.scan(vec![root], false).await })
+        };
+
+        let hovers: Vec<_> = (0..8)
+            .map(|_| {
+                let backend = backend.clone();
+                let a = a.clone();
+                tokio::spawn(async move {
+                    for _ in 0..100 {
+                        let hover = backend
+                            .hover(HoverParams {
+                                text_document_position_params: TextDocumentPositionParams {
+                                    text_document: TextDocumentIdentifier { uri: a.clone() },
+                                    position: Position::new(1, 7),
+                                },
+                                work_done_progress_params: Default::default(),
+                            })
+                            .await
+                            .unwrap()
+                            .expect("the link always resolves");
+                        match hover.contents {
+                            HoverContents::Markup(markup) => assert!(markup.value.contains("**B**")),
+                            other => panic!("expected markdown, got {:?}", other),
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let all = async {
+            scan.await.unwrap();
+            for hover in hovers {
+                hover.await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), all).await.expect("hovers and the scan finish");
+        assert_eq!(backend.index.read().await.notes.len(), 2 + 1000);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    /// Apply a workspace edit to the files on disk, as an editor would, and
+    /// return the changes its watcher would report.
+    fn apply_to_disk(edit: WorkspaceEdit) -> Vec<FileEvent> {
+        let operations = match edit.document_changes {
+            Some(DocumentChanges::Operations(operations)) => operations,
+            other => panic!("expected document operations, got {:?}", other),
+        };
+        let mut events = Vec::new();
+        for operation in operations {
+            match operation {
+                DocumentChangeOperation::Edit(edit) => {
+                    let path = edit.text_document.uri.to_file_path().unwrap();
+                    let content = std::fs::read_to_string(&path).unwrap();
+                    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+                    for edit in edit.edits.into_iter().rev() {
+                        let edit = match edit {
+                            OneOf::Left(edit) => edit,
+                            OneOf::Right(annotated) => annotated.text_edit,
+                        };
+                        let line = &mut lines[edit.range.start.line as usize];
+                        let bytes = crate::offsets::byte_range(line, &edit.range);
+                        line.replace_range(bytes, &edit.new_text);
+                    }
+                    std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+                    events.push(FileEvent::new(edit.text_document.uri, FileChangeType::CHANGED));
+                }
+                DocumentChangeOperation::Op(ResourceOp::Rename(rename)) => {
+                    let old = rename.old_uri.to_file_path().unwrap();
+                    std::fs::rename(old, rename.new_uri.to_file_path().unwrap()).unwrap();
+                    events.push(FileEvent::new(rename.old_uri, FileChangeType::DELETED));
+                    events.push(FileEvent::new(rename.new_uri, FileChangeType::CREATED));
+                }
+                other => panic!("unexpected operation {:?}", other),
+            }
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn rename_reconciles_through_watcher() {
+        let root = vault(
+            "rename",
+            &[("old.note", "# Old\n"), ("ref.note", "# Ref\nSee [[old]] and [[old::Intro]].\n")],
+        );
+        let id_of = |file: &str| NoteID::from_path(&root.join(file)).unwrap();
+        let mut index = Index::new();
+        index.add_root(root.clone());
+        for file in ["old.note", "ref.note"] {
+            index.insert_note(Note::of_file(&root.join(file)).unwrap());
+        }
+        let (service, _socket) = serve(index);
+        let backend = service.inner();
+        let rename = |new_name: &str| RenameParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: Url::from_file_path(root.join("ref.note")).unwrap(),
+                },
+                position: Position::new(1, 7),
+            },
+            new_name: new_name.to_string(),
+            work_done_progress_params: Default::default(),
+        };
+
+        // A kind in the new name would be written into every link.
+        assert!(backend.rename(rename("new (article)")).await.is_err());
+
+        let edit = backend.rename(rename("New Name")).await.unwrap().expect("the link can be renamed");
+        // Until the edit is applied, the index is as it was.
+        let (old, new, reference) = (id_of("old.note"), id_of("new-name.note"), id_of("ref.note"));
+        assert!(backend.index.read().await.notes.contains_key(&old));
+
+        let changes = apply_to_disk(edit);
+        backend.did_change_watched_files(DidChangeWatchedFilesParams { changes }).await;
+
+        let index = backend.index.read().await;
+        assert!(!index.notes.contains_key(&old));
+        assert!(index.notes.contains_key(&new));
+        assert_eq!(
+            std::fs::read_to_string(root.join("ref.note")).unwrap(),
+            "# Ref\nSee [[New Name]] and [[New Name::Intro]].\n"
+        );
+        let link = NoteID { name: new.name.clone(), kind: NoteKind::Any };
+        assert!(index.notes[&reference].outlinks.contains(&link));
+        assert!(index.backlinks_to(&new).contains_key(&reference));
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,77 @@
+//! LSP positions count UTF-16 code units along a line, but Rust strings are
+//! indexed by byte. These convert between the two for a single line.
+
+use tower_lsp::lsp_types::{Position, Range};
+
+/// The byte offset in `line` of a UTF-16 column. Columns past the end of the
+/// line, or in the middle of a character, are clamped to a char boundary.
+pub fn byte_col(line: &str, utf16_col: u32) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= utf16_col as usize {
+            return i;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+/// The UTF-16 column of a byte offset in `line`.
+pub fn utf16_col(line: &str, byte_col: usize) -> u32 {
+    let byte_col = byte_col.min(line.len());
+    line.char_indices()
+        .take_while(|(i, _)| *i < byte_col)
+        .map(|(_, c)| c.len_utf16() as u32)
+        .sum()
+}
+
+/// The range covering bytes `start..end` of `line`, which is row `row`.
+pub fn range(line: &str, row: usize, start: usize, end: usize) -> Range {
+    Range::new(
+        Position::new(row as u32, utf16_col(line, start)),
+        Position::new(row as u32, utf16_col(line, end)),
+    )
+}
+
+/// The bytes of `line` covered by the columns of `range`.
+pub fn byte_range(line: &str, range: &Range) -> std::ops::Range<usize> {
+    byte_col(line, range.start.character)..byte_col(line, range.end.character)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_round_trip() {
+        // Two bytes and one unit; three bytes and one unit; four bytes and
+        // two units.
+        for (line, units) in [("é", 1), ("日本", 2), ("😀", 2), ("a😀b", 4)] {
+            assert_eq!(utf16_col(line, line.len()), units);
+            assert_eq!(byte_col(line, units), line.len());
+            for (i, _) in line.char_indices() {
+                assert_eq!(byte_col(line, utf16_col(line, i)), i);
+            }
+        }
+    }
+
+    #[test]
+    fn columns_are_clamped() {
+        // Past the end of the line.
+        assert_eq!(byte_col("日本", 10), "日本".len());
+        assert_eq!(utf16_col("日本", 100), 2);
+        // Between the two halves of a surrogate pair.
+        assert_eq!(byte_col("😀x", 1), "😀".len());
+    }
+
+    #[test]
+    fn ranges_round_trip() {
+        let line = "é [[日本]] 😀 [[a]]";
+        let start = line.find("[[a").unwrap();
+        let range = range(line, 3, start, line.len());
+        assert_eq!(range.start, Position::new(3, 12));
+        assert_eq!(range.end, Position::new(3, 17));
+        assert_eq!(byte_range(line, &range), start..line.len());
+        assert_eq!(&line[byte_range(line, &range)], "[[a]]");
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use tower_lsp::lsp_types::{Position, Range, SelectionRange};
+
+use crate::index::{pattern, Heading, LINK_RE};
+use crate::offsets;
+
+static LIST_ITEM_RE: LazyLock<Regex> =
+    LazyLock::new(|| pattern("list item", r"^\s*(?:[-*+]|\d+[.)])\s+\S"));
+static EMPHASIS_RE: LazyLock<Regex> =
+    LazyLock::new(|| pattern("emphasis", r"\*\*[^*]+\*\*|\*[^*\s][^*]*\*|\b_[^_]+_\b|`[^`]+`"));
+
+/// Compile this module's patterns; see `index::compile_patterns`.
+pub fn compile_patterns() {
+    LazyLock::force(&LIST_ITEM_RE);
+    LazyLock::force(&EMPHASIS_RE);
+}
+
+/// The ranges that expanding the selection at `position` steps through,
+/// from the word under the cursor out to the whole note: word, link or
+/// emphasis, list item, paragraph, each enclosing section, document.
+pub fn selection_range(content: &str, headings: &[Heading], position: Position) -> SelectionRange {
+    let lines: Vec<&str> = content.lines().collect();
+    let row = position.line as usize;
+    let mut ranges = Vec::new();
+
+    if let Some(line) = lines.get(row) {
+        let col = offsets::byte_col(line, position.character);
+
+        // The word under the cursor.
+        let is_word = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+        let start = line[..col]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| is_word(*c))
+            .last()
+            .map_or(col, |(i, _)| i);
+        let end = line[col..].find(|c| !is_word(c)).map_or(line.len(), |i| col + i);
+        if start < end {
+            ranges.push(offsets::range(line, row, start, end));
+        }
+
+        // The `[[link]]` and emphasis it's in, smallest first, since either
+        // can hold the other.
+        let mut spans: Vec<_> = LINK_RE
+            .find_iter(line)
+            .chain(EMPHASIS_RE.find_iter(line))
+            .filter(|m| m.start() <= col && col < m.end())
+            .collect();
+        spans.sort_by_key(|m| m.len());
+        ranges.extend(spans.iter().map(|m| offsets::range(line, row, m.start(), m.end())));
+
+        // The list item, without its indentation.
+        if LIST_ITEM_RE.is_match(line) {
+            let indent = line.len() - line.trim_start().len();
+            ranges.push(offsets::range(line, row, indent, line.trim_end().len()));
+        }
+
+        // The paragraph: the run of lines around this one with no blank
+        // line or heading between them.
+        let is_heading = |row: usize| headings.iter().any(|h| h.range.start.line as usize == row);
+        let in_paragraph = |row: usize| !lines[row].trim().is_empty() && !is_heading(row);
+        if in_paragraph(row) {
+            let first = (0..row).rev().take_while(|&r| in_paragraph(r)).last().unwrap_or(row);
+            let last = (row + 1..lines.len()).take_while(|&r| in_paragraph(r)).last().unwrap_or(row);
+            ranges.push(Range::new(
+                Position::new(first as u32, 0),
+                Position::new(last as u32, offsets::utf16_col(lines[last], lines[last].len())),
+            ));
+        }
+    }
+
+    // Each section the position is in, innermost first.
+    ranges.extend(
+        headings
+            .iter()
+            .rev()
+            .filter(|h| h.section.start.line <= position.line && position.line <= h.section.end.line)
+            .map(|h| h.section),
+    );
+
+    let last = lines.last().copied().unwrap_or("");
+    ranges.push(Range::new(
+        Position::new(0, 0),
+        Position::new(lines.len().saturating_sub(1) as u32, offsets::utf16_col(last, last.len())),
+    ));
+
+    // Clients reject a chain unless each step strictly contains the last, so
+    // drop any that don't (e.g. emphasis that only partly covers a link).
+    let mut chain: Vec<Range> = Vec::new();
+    for range in ranges {
+        if chain.last().is_none_or(|inner| range != *inner && contains(&range, inner)) {
+            chain.push(range);
+        }
+    }
+
+    let mut selection = None;
+    for range in chain.into_iter().rev() {
+        selection = Some(SelectionRange { range, parent: selection.map(Box::new) });
+    }
+    selection.expect("there is always the document range")
+}
+
+/// Whether `outer` covers all of `inner`.
+fn contains(outer: &Range, inner: &Range) -> bool {
+    let key = |p: &Position| (p.line, p.character);
+    key(&outer.start) <= key(&inner.start) && key(&inner.end) <= key(&outer.end)
+}
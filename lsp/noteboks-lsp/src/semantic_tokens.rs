@@ -0,0 +1,172 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use tower_lsp::lsp_types::{
+    SemanticToken, SemanticTokenModifier, SemanticTokenType, SemanticTokensLegend,
+};
+
+use crate::index::{body_lines, pattern, scan_links, scan_tags, todo_keywords};
+use crate::offsets;
+
+static CHECKBOX_RE: LazyLock<Regex> = LazyLock::new(|| pattern("checkbox", r"^\s*[-*+]\s+(\[[ xX]\])"));
+static TIMESTAMP_RE: LazyLock<Regex> =
+    LazyLock::new(|| pattern("timestamp", r"\b\d{4}-\d{2}-\d{2}(?:[ T]\d{2}:\d{2})?\b"));
+
+/// Compile this module's patterns; see `index::compile_patterns`.
+pub fn compile_patterns() {
+    LazyLock::force(&CHECKBOX_RE);
+    LazyLock::force(&TIMESTAMP_RE);
+}
+
+/// Token types, in legend order.
+const LINK: u32 = 0;
+const TAG: u32 = 1;
+const TASK: u32 = 2;
+const TIMESTAMP: u32 = 3;
+
+/// Token modifier bits, in legend order.
+const BROKEN: u32 = 1 << 0;
+
+pub fn legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: vec![
+            SemanticTokenType::STRING,
+            SemanticTokenType::DECORATOR,
+            SemanticTokenType::KEYWORD,
+            SemanticTokenType::NUMBER,
+        ],
+        // Links to notes that don't exist are marked deprecated, so themes
+        // can dim (or strike through) them.
+        token_modifiers: vec![SemanticTokenModifier::DEPRECATED],
+    }
+}
+
+/// Classify the links, tags, task markers (`[ ]`, `[x]`, and a headline's
+/// `TODO`, `DONE` or `#+TODO:` keyword) and dates in a note, delta-encoded as
+/// the protocol expects. `resolves` says whether a link's text points at an
+/// existing note.
+pub fn tokens(content: &str, resolves: impl Fn(&str) -> bool) -> Vec<SemanticToken> {
+    // (line, start, length, type, modifiers)
+    let mut found: Vec<(u32, u32, u32, u32, u32)> = Vec::new();
+
+    for (text, range) in scan_links(content) {
+        let modifiers = if resolves(&text) { 0 } else { BROKEN };
+        let length = range.end.character - range.start.character;
+        found.push((range.start.line, range.start.character, length, LINK, modifiers));
+    }
+    for (_, range) in scan_tags(content) {
+        let length = range.end.character - range.start.character;
+        found.push((range.start.line, range.start.character, length, TAG, 0));
+    }
+    let (open, done) = todo_keywords(content);
+    for (row, line) in body_lines(content) {
+        if let Some(m) = CHECKBOX_RE.captures(line).and_then(|cap| cap.get(1)) {
+            let range = offsets::range(line, row, m.start(), m.end());
+            let length = range.end.character - range.start.character;
+            found.push((row as u32, range.start.character, length, TASK, 0));
+        }
+        // Only the first word of a headline is a keyword, as in `Note::tasks`.
+        let heading = line.trim_start_matches('#').strip_prefix(' ').filter(|_| line.starts_with('#'));
+        if let Some(heading) = heading {
+            let heading = heading.trim_start();
+            let keyword = heading.split_whitespace().next().unwrap_or("");
+            if open.iter().chain(&done).any(|k| k == keyword) {
+                let start = line.len() - heading.len();
+                let range = offsets::range(line, row, start, start + keyword.len());
+                let length = range.end.character - range.start.character;
+                found.push((row as u32, range.start.character, length, TASK, 0));
+            }
+        }
+        for m in TIMESTAMP_RE.find_iter(line) {
+            let range = offsets::range(line, row, m.start(), m.end());
+            let length = range.end.character - range.start.character;
+            found.push((row as u32, range.start.character, length, TIMESTAMP, 0));
+        }
+    }
+
+    // Tokens mustn't overlap; where they would (e.g. a date inside a link),
+    // the first one wins.
+    found.sort();
+    let mut tokens = Vec::with_capacity(found.len());
+    let (mut prev_line, mut prev_start, mut prev_end) = (0, 0, 0);
+    for (line, start, length, token_type, modifiers) in found {
+        if line == prev_line && start < prev_end && !tokens.is_empty() {
+            continue;
+        }
+        let delta_line = line - prev_line;
+        let delta_start = if delta_line == 0 { start - prev_start } else { start };
+        tokens.push(SemanticToken {
+            delta_line,
+            delta_start,
+            length,
+            token_type,
+            token_modifiers_bitset: modifiers,
+        });
+        (prev_line, prev_start, prev_end) = (line, start, start + length);
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tokens as (line, start, length, type, modifiers), with the delta
+    /// encoding undone.
+    fn absolute(tokens: &[SemanticToken]) -> Vec<(u32, u32, u32, u32, u32)> {
+        let (mut line, mut start) = (0, 0);
+        tokens
+            .iter()
+            .map(|t| {
+                start = if t.delta_line == 0 { start + t.delta_start } else { t.delta_start };
+                line += t.delta_line;
+                (line, start, t.length, t.token_type, t.token_modifiers_bitset)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delta_encoding() {
+        let content = "# Title\n- [ ] see [[a]] #tag\n\nOn 2024-01-02 and [[b]]\n";
+        let tokens = tokens(content, |_| true);
+        let deltas: Vec<_> = tokens.iter().map(|t| (t.delta_line, t.delta_start)).collect();
+        assert_eq!(deltas, vec![(1, 2), (0, 8), (0, 6), (2, 3), (0, 15)]);
+        assert_eq!(
+            absolute(&tokens),
+            vec![
+                (1, 2, 3, TASK, 0),
+                (1, 10, 5, LINK, 0),
+                (1, 16, 4, TAG, 0),
+                (3, 3, 10, TIMESTAMP, 0),
+                (3, 18, 5, LINK, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlapping_tokens() {
+        // The date is inside the link, which starts first and wins.
+        let tokens = absolute(&tokens("x\n[[2024-01-02]]\n", |_| true));
+        assert_eq!(tokens, vec![(1, 0, 14, LINK, 0)]);
+    }
+
+    #[test]
+    fn broken_links_are_deprecated() {
+        let tokens = absolute(&tokens("x\n[[here]] [[gone]]\n", |text| text == "here"));
+        assert_eq!(tokens, vec![(1, 0, 8, LINK, 0), (1, 9, 8, LINK, BROKEN)]);
+    }
+
+    #[test]
+    fn keywords_only_start_headlines() {
+        let tokens = absolute(&tokens("x\nAdd it to the TODO list.\n## DONE Ship\n- TODO later\n", |_| true));
+        assert_eq!(tokens, vec![(2, 3, 4, TASK, 0)]);
+    }
+
+    #[test]
+    fn custom_keywords() {
+        let content = "#+TODO: NEXT | DONE\n# Plan\n## NEXT Write\n## TODO Not a keyword here\n";
+        let tokens = absolute(&tokens(content, |_| true));
+        assert_eq!(tokens, vec![(2, 3, 4, TASK, 0)]);
+    }
+}
@@ -0,0 +1,65 @@
+use serde_json::Value;
+
+/// User settings, read from the client's `initializationOptions` (in Zed,
+/// `lsp.noteboks-lsp.initialization_options`).
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// The most lines of a linked note's opening paragraph to show when
+    /// hovering a link.
+    pub preview_lines: usize,
+    /// The most backlinks listed when hovering a note's title.
+    pub hover_backlinks: usize,
+    /// How daily notes are named, with `YYYY`, `MM` and `DD` placeholders.
+    pub daily_note_format: String,
+    /// The content of a new daily note, with `{{date}}` replaced by its name.
+    /// Without one, daily notes start with just a title heading.
+    pub daily_note_template: Option<String>,
+    /// Extra note kinds, as `(extension, display name)`, from a `kinds`
+    /// object like `{ "recipe": "Recipe" }`.
+    pub kinds: Vec<(String, String)>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            preview_lines: 10,
+            hover_backlinks: 25,
+            daily_note_format: "YYYY-MM-DD".to_string(),
+            daily_note_template: None,
+            kinds: vec![],
+        }
+    }
+}
+
+impl Settings {
+    /// Read settings from initialization options, keeping the default for
+    /// anything missing or malformed.
+    pub fn from_options(options: Option<&Value>) -> Self {
+        let mut settings = Settings::default();
+        let options = match options {
+            Some(options) => options,
+            None => return settings,
+        };
+
+        if let Some(n) = options.get("previewLines").and_then(Value::as_u64) {
+            settings.preview_lines = n as usize;
+        }
+        if let Some(n) = options.get("hoverBacklinks").and_then(Value::as_u64) {
+            settings.hover_backlinks = n as usize;
+        }
+        if let Some(format) = options.get("dailyNoteFormat").and_then(Value::as_str) {
+            settings.daily_note_format = format.to_string();
+        }
+        if let Some(template) = options.get("dailyNoteTemplate").and_then(Value::as_str) {
+            settings.daily_note_template = Some(template.to_string());
+        }
+        if let Some(kinds) = options.get("kinds").and_then(Value::as_object) {
+            settings.kinds = kinds
+                .iter()
+                .map(|(ext, name)| (ext.clone(), name.as_str().unwrap_or(ext).to_string()))
+                .collect();
+        }
+
+        settings
+    }
+}
@@ -14,7 +14,7 @@ impl zed::Extension for Noteboks {
     ) -> zed::Result<zed::Command> {
         let path = worktree
             .which("noteboks-lsp")
-            .ok_or_else(|| "noteboks-lsp must be installed and on PATH")?;
+            .ok_or("noteboks-lsp must be installed and on PATH")?;
 
         Ok(zed::Command {
             command: path,